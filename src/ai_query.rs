@@ -1,12 +1,77 @@
+use crate::Error;
 use serde::Serialize;
 use serde_json::Value;
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    io::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Sent after the code when a response is requested again because it couldn't be parsed.
+const REPARSE_REMINDER: &str = "Respond with ONLY the JSON per the schema.";
+/// System prompt of [`AI::explain`], answered in prose instead of the response format.
+const EXPLAIN_PROMPT: &str = "You are a code reviewer. Explain in a few sentences of prose whether and why the question stated in the system prompt applies to the code fragment provided in the user prompt. The code is cut arbitrarily from the source file.";
+
+/// How often a request rejected with 429 Too Many Requests is sent again before failing.
+const RATE_LIMITED_RETRIES: u32 = 5;
+/// Wait before the first retry of a rate limited request without a `Retry-After` header,
+/// doubled for each further one.
+const RATE_LIMITED_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait honored from a `Retry-After` header.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(600);
+/// Up to this fraction of a retry wait is added at random, so parallel requests rejected at the
+/// same time don't all come back at once.
+const RETRY_JITTER: f64 = 0.5;
 
 pub trait AiQueryConfig: Debug + Send {
     fn system_prompt(&self) -> String;
     fn response_format(&self) -> Value;
     fn max_tokens(&self) -> usize;
-    fn extract_result(&self, content: &str) -> anyhow::Result<f32>;
+    fn extract_result(&self, content: &str) -> crate::Result<f32>;
+    /// Explanation of the model for its result, if the response format provides one.
+    fn extract_reason(&self, _content: &str) -> Option<String> {
+        None
+    }
+
+    /// System prompt when several numbered fragments are evaluated in one request.
+    fn batch_system_prompt(&self) -> String {
+        format!(
+            "{} The user prompt contains several code fragments, each introduced by a line '### Fragment N'. Evaluate each fragment on its own and return one result per fragment in the given order.",
+            self.system_prompt()
+        )
+    }
+
+    /// Response format of a batch, by default an array of single results under `results`.
+    fn batch_response_format(&self) -> Value {
+        let mut format = self.response_format();
+        let schema = format["json_schema"]["schema"].take();
+        format["json_schema"]["name"] = "scores".into();
+        format["json_schema"]["schema"] = serde_json::json!({
+            "type": "object",
+            "additionalProperties": false,
+            "properties": {
+                "results": { "type": "array", "items": schema }
+            },
+            "required": ["results"]
+        });
+        format
+    }
+
+    /// Splits a batch response into the contents of the single results, which are then passed to
+    /// [`AiQueryConfig::extract_result`] and [`AiQueryConfig::extract_reason`].
+    fn split_batch(&self, content: &str) -> crate::Result<Vec<String>> {
+        let content: Value = serde_json::from_str(content)
+            .map_err(|e| Error::Parse(format!("error parsing {}: {}", content, e)))?;
+        let results = content["results"].as_array().ok_or(Error::Parse(format!(
+            "Results not found in response {}",
+            content
+        )))?;
+        Ok(results.iter().map(Value::to_string).collect())
+    }
 }
 
 impl<T: AiQueryConfig + 'static> From<T> for Box<dyn AiQueryConfig> {
@@ -44,16 +109,76 @@ impl AiQueryConfig for DefaultAiQueryConfig {
         10000
     }
 
-    fn extract_result(&self, content: &str) -> anyhow::Result<f32> {
+    fn extract_result(&self, content: &str) -> crate::Result<f32> {
         let content: Value = serde_json::from_str(content)
-            .map_err(|e| anyhow::anyhow!("error parsing {}: {}", content, e))?;
-        let result = content["score"]
-            .as_f64()
-            .ok_or(anyhow::anyhow!("Score not found in response {}", content))?
-            as f32;
+            .map_err(|e| Error::Parse(format!("error parsing {}: {}", content, e)))?;
+        let result = content["score"].as_f64().ok_or(Error::Parse(format!(
+            "Score not found in response {}",
+            content
+        )))? as f32;
 
         Ok(result)
     }
+
+    fn extract_reason(&self, content: &str) -> Option<String> {
+        let content: Value = serde_json::from_str(content).ok()?;
+        content["reason"].as_str().map(str::to_string)
+    }
+}
+
+/// [`DefaultAiQueryConfig`] with a custom JSON schema for the response, which still has to
+/// provide the `score`.
+#[derive(Clone, Debug)]
+pub struct SchemaAiQueryConfig {
+    schema: Value,
+}
+
+impl SchemaAiQueryConfig {
+    pub fn new(schema: Value) -> crate::Result<Self> {
+        if !schema.is_object() {
+            return Err(Error::Parse("Schema must be a JSON object".to_string()));
+        }
+        if schema["properties"]["score"].is_null() {
+            return Err(Error::Parse(
+                "Schema must have a score property".to_string(),
+            ));
+        }
+        Ok(Self { schema })
+    }
+
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let schema = serde_json::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|e| Error::Parse(format!("error parsing schema {}: {}", path.display(), e)))?;
+        Self::new(schema)
+    }
+}
+
+impl AiQueryConfig for SchemaAiQueryConfig {
+    fn system_prompt(&self) -> String {
+        DefaultAiQueryConfig.system_prompt()
+    }
+
+    fn response_format(&self) -> Value {
+        serde_json::json!({"type": "json_schema",
+        "json_schema": {
+            "strict": true,
+            "name": "score",
+            "schema": self.schema
+        }})
+    }
+
+    fn max_tokens(&self) -> usize {
+        DefaultAiQueryConfig.max_tokens()
+    }
+
+    fn extract_result(&self, content: &str) -> crate::Result<f32> {
+        DefaultAiQueryConfig.extract_result(content)
+    }
+
+    fn extract_reason(&self, content: &str) -> Option<String> {
+        DefaultAiQueryConfig.extract_reason(content)
+    }
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -69,7 +194,14 @@ struct ChatRequest {
     temperature: Option<f32>,
     max_completion_tokens: usize,
     stream: bool,
+    /// Asks for the usage in the last event of a streamed response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<Value>,
+    /// Left out for free-form answers, see [`AI::explain`].
+    #[serde(skip_serializing_if = "Value::is_null")]
     response_format: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -78,6 +210,9 @@ struct ChatRequestFactory {
     temperature: Option<f32>,
     ai_query_config: Box<dyn AiQueryConfig>,
     question: String,
+    stream: bool,
+    /// Appended to the system prompt, see [`AI::with_context`].
+    context: Option<String>,
 }
 
 impl ChatRequestFactory {
@@ -93,17 +228,22 @@ impl ChatRequestFactory {
             temperature,
             ai_query_config,
             question,
+            stream: false,
+            context: None,
         }
     }
 
-    fn create_system_message(&self) -> ChatRequestMessage {
+    fn create_system_message(&self, system_prompt: String) -> ChatRequestMessage {
+        let mut content = format!("{} Question: {}", system_prompt, self.question);
+        if let Some(context) = &self.context {
+            content.push_str(&format!(
+                "\n\nThe code fragments are taken from the following files:\n\n{}",
+                context
+            ));
+        }
         ChatRequestMessage {
             role: "system".to_string(),
-            content: format!(
-                "{} Question: {}",
-                self.ai_query_config.system_prompt(),
-                self.question
-            ),
+            content,
         }
     }
 
@@ -115,32 +255,464 @@ impl ChatRequestFactory {
     }
 
     fn create(&self, code: impl Into<String>) -> ChatRequest {
+        self.create_with(
+            self.ai_query_config.system_prompt(),
+            code.into(),
+            self.ai_query_config.response_format(),
+        )
+    }
+
+    fn create_batch(&self, codes: &[impl AsRef<str>]) -> ChatRequest {
+        let content = codes
+            .iter()
+            .enumerate()
+            .map(|(idx, code)| format!("### Fragment {}\n{}", idx + 1, code.as_ref()))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        self.create_with(
+            self.ai_query_config.batch_system_prompt(),
+            content,
+            self.ai_query_config.batch_response_format(),
+        )
+    }
+
+    fn create_with(
+        &self,
+        system_prompt: String,
+        content: String,
+        response_format: Value,
+    ) -> ChatRequest {
         let messages = vec![
-            self.create_system_message(),
-            self.create_user_message(code.into()),
+            self.create_system_message(system_prompt),
+            self.create_user_message(content),
         ];
-        let response_format = self.ai_query_config.response_format();
         let max_completion_tokens = self.ai_query_config.max_tokens();
         ChatRequest {
             model: self.model.clone(),
             messages,
             temperature: self.temperature,
             max_completion_tokens,
-            stream: false,
+            stream: self.stream,
+            stream_options: self
+                .stream
+                .then(|| serde_json::json!({"include_usage": true})),
             response_format,
+            n: None,
+        }
+    }
+
+    /// Serialized request for `code`, asking for `n` choices if given and overriding the
+    /// configured temperature with `temperature` if given. With `remind`, the request ends with
+    /// a reminder of the response format.
+    fn create_json(
+        &self,
+        code: impl Into<String>,
+        n: Option<usize>,
+        temperature: Option<f32>,
+        remind: bool,
+    ) -> crate::Result<String> {
+        self.to_json(self.create(code), n, temperature, remind)
+    }
+
+    fn create_batch_json(
+        &self,
+        codes: &[impl AsRef<str>],
+        n: Option<usize>,
+        temperature: Option<f32>,
+        remind: bool,
+    ) -> crate::Result<String> {
+        self.to_json(self.create_batch(codes), n, temperature, remind)
+    }
+
+    /// Serialized request for an answer in prose about `code`, see [`AI::explain`].
+    fn create_explain_json(&self, code: impl Into<String>) -> crate::Result<String> {
+        let chat_request = self.create_with(EXPLAIN_PROMPT.to_string(), code.into(), Value::Null);
+        self.to_json(chat_request, None, None, false)
+    }
+
+    fn to_json(
+        &self,
+        mut chat_request: ChatRequest,
+        n: Option<usize>,
+        temperature: Option<f32>,
+        remind: bool,
+    ) -> crate::Result<String> {
+        if remind {
+            chat_request
+                .messages
+                .push(self.create_user_message(REPARSE_REMINDER.to_string()));
         }
+        let temperature = temperature.or(chat_request.temperature);
+        Ok(serde_json::to_string(&ChatRequest {
+            n,
+            temperature,
+            ..chat_request
+        })?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    fn from_response(response: &Value) -> Option<Self> {
+        let usage = response.get("usage")?;
+        Some(Self {
+            prompt_tokens: usage.get("prompt_tokens")?.as_u64()?,
+            completion_tokens: usage.get("completion_tokens")?.as_u64()?,
+        })
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
     }
+}
+
+impl std::ops::Add for Usage {
+    type Output = Self;
 
-    fn create_json(&self, code: impl Into<String>) -> anyhow::Result<String> {
-        Ok(serde_json::to_string(&self.create(code))?)
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            prompt_tokens: self.prompt_tokens + rhs.prompt_tokens,
+            completion_tokens: self.completion_tokens + rhs.completion_tokens,
+        }
+    }
+}
+
+/// Collects the content of a streamed response from its server sent events.
+#[derive(Debug, Default)]
+struct StreamAccumulator {
+    buffer: Vec<u8>,
+    content: String,
+    refusal: String,
+    usage: Option<Usage>,
+    done: bool,
+}
+
+impl StreamAccumulator {
+    /// Feeds a chunk of the response body, returns whether the content changed.
+    fn push(&mut self, chunk: &[u8]) -> crate::Result<bool> {
+        self.buffer.extend_from_slice(chunk);
+        let mut changed = false;
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim_end().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                self.done = true;
+                break;
+            }
+            let event: Value = serde_json::from_str(data)
+                .map_err(|e| Error::Parse(format!("error parsing stream event {}: {}", data, e)))?;
+            if let Some(usage) = Usage::from_response(&event) {
+                self.usage = Some(usage);
+            }
+            if let Some(delta) = event["choices"][0]["delta"]["content"].as_str() {
+                self.content.push_str(delta);
+                changed = true;
+            }
+            if let Some(delta) = event["choices"][0]["delta"]["refusal"].as_str() {
+                self.refusal.push_str(delta);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Takes the last event at the end of the body, also without a line break after it.
+    fn finish(&mut self) -> crate::Result<bool> {
+        if self.buffer.is_empty() {
+            return Ok(false);
+        }
+        self.push(b"\n")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryResult {
+    pub value: f32,
+    pub reason: Option<String>,
+    pub usage: Option<Usage>,
+    /// Standard deviation of the value across samples, if more than one was taken.
+    pub std_dev: Option<f32>,
+    /// Content of the model response the value was extracted from.
+    pub raw: String,
+}
+
+impl QueryResult {
+    /// Combines the results of several samples of the same query into their mean value, with the
+    /// reason and response of the first sample and the summed usage.
+    fn aggregate(mut samples: Vec<QueryResult>) -> crate::Result<QueryResult> {
+        if samples.len() <= 1 {
+            return samples
+                .pop()
+                .ok_or(Error::Parse("No samples to aggregate".to_string()));
+        }
+        let count = samples.len() as f32;
+        let mean = samples.iter().map(|sample| sample.value).sum::<f32>() / count;
+        let variance = samples
+            .iter()
+            .map(|sample| (sample.value - mean).powi(2))
+            .sum::<f32>()
+            / count;
+        let usage = samples
+            .iter()
+            .filter_map(|sample| sample.usage)
+            .reduce(|a, b| a + b);
+        let first = samples.swap_remove(0);
+        Ok(QueryResult {
+            value: mean,
+            reason: first.reason,
+            usage,
+            std_dev: Some(variance.sqrt()),
+            raw: first.raw,
+        })
+    }
+}
+
+/// How the auth token is sent to the endpoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AuthScheme {
+    /// `Authorization: Bearer <token>`
+    #[default]
+    Bearer,
+    /// `x-api-key: <token>`
+    XApiKey,
+    /// `Authorization: <token>`
+    Raw,
+}
+
+fn with_auth(
+    request: reqwest::RequestBuilder,
+    auth_token: &Option<String>,
+    auth_scheme: AuthScheme,
+) -> reqwest::RequestBuilder {
+    let Some(auth_token) = auth_token else {
+        return request;
+    };
+    match auth_scheme {
+        AuthScheme::Bearer => request.bearer_auth(auth_token),
+        AuthScheme::XApiKey => request.header("x-api-key", auth_token),
+        AuthScheme::Raw => request.header(reqwest::header::AUTHORIZATION, auth_token),
+    }
+}
+
+fn add_usage(a: Option<Usage>, b: Option<Usage>) -> Option<Usage> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Message contents of all choices of a chat completion response.
+fn choice_contents(response: &Value) -> crate::Result<Vec<String>> {
+    let choices = response
+        .get("choices")
+        .and_then(Value::as_array)
+        .ok_or(Error::Parse(format!(
+            "No choices in response: {:?}",
+            response
+        )))?;
+    if choices.is_empty() {
+        return Err(Error::Parse(format!(
+            "No choice in response: {:?}",
+            response
+        )));
+    }
+    choices
+        .iter()
+        .map(|choice| {
+            let message = choice.get("message").ok_or(Error::Parse(format!(
+                "No message in response: {:?}",
+                choice
+            )))?;
+            // safety tuned models answer with a null content and the reason in `refusal`
+            if let Some(refusal) = message.get("refusal").and_then(Value::as_str)
+                && message.get("content").is_none_or(Value::is_null)
+            {
+                return Err(Error::Refusal(refusal.to_string()));
+            }
+            let content = message.get("content").ok_or(Error::Parse(format!(
+                "No content in response: {:?}",
+                message
+            )))?;
+            let content = content.as_str().ok_or(Error::Parse(format!(
+                "No string content in response: {:?}",
+                content
+            )))?;
+            Ok(content.to_string())
+        })
+        .collect()
+}
+
+/// Model ids of an OpenAI compatible `/models` response.
+fn model_ids(response: &Value) -> crate::Result<Vec<String>> {
+    let data = response
+        .get("data")
+        .and_then(Value::as_array)
+        .ok_or(Error::Parse(format!(
+            "No model list in response: {:?}",
+            response
+        )))?;
+    Ok(data
+        .iter()
+        .filter_map(|model| model.get("id").and_then(Value::as_str))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Client sending all requests through `proxy` except to hosts in `no_proxy`, or through the
+/// proxies of the environment like `HTTPS_PROXY` without `proxy`.
+fn client(
+    proxy: Option<&str>,
+    no_proxy: Option<reqwest::NoProxy>,
+) -> crate::Result<reqwest::Client> {
+    let builder = reqwest::Client::builder();
+    let builder = match proxy {
+        Some(proxy) => builder.proxy(reqwest::Proxy::all(proxy)?.no_proxy(no_proxy)),
+        None => builder,
+    };
+    Ok(builder.build()?)
+}
+
+/// `path` below the endpoint at `url`.
+fn endpoint_url(url: &str, path: &str) -> crate::Result<reqwest::Url> {
+    let url = format!("{}/{}", url, path);
+    reqwest::Url::parse(&url).map_err(|e| Error::Url(format!("{}: {}", url, e)))
+}
+
+/// Lists the ids of the models served by the endpoint at `url`, see [`AI::with_proxy`] for
+/// `proxy`.
+pub async fn list_models(
+    url: impl AsRef<str>,
+    auth_token: Option<String>,
+    auth_scheme: AuthScheme,
+    proxy: Option<&str>,
+) -> crate::Result<Vec<String>> {
+    let client = client(proxy, reqwest::NoProxy::from_env())?;
+    let url = endpoint_url(url.as_ref(), "models")?;
+    let request = with_auth(client.get(url), &auth_token, auth_scheme).build()?;
+    let response = check_auth(client.execute(request).await?)
+        .await?
+        .error_for_status()?;
+    let response: Value = serde_json::from_str(&response.text().await?)?;
+    model_ids(&response)
+}
+
+/// Fails with [`Error::Auth`] if the endpoint rejected the auth token.
+async fn check_auth(response: reqwest::Response) -> crate::Result<reqwest::Response> {
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        return Err(Error::Auth {
+            status: status.as_u16(),
+            body: response.text().await?,
+        });
+    }
+    Ok(response)
+}
+
+/// JSON line for the request log with the url, headers and body of `request`. Auth headers are
+/// redacted.
+fn request_log_entry(request: &reqwest::Request) -> Value {
+    let headers: serde_json::Map<String, Value> = request
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = if name == reqwest::header::AUTHORIZATION || name == "x-api-key" {
+                "<redacted>".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).to_string()
+            };
+            (name.to_string(), Value::String(value))
+        })
+        .collect();
+    let body = request
+        .body()
+        .and_then(reqwest::Body::as_bytes)
+        .map(|body| {
+            serde_json::from_slice(body)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).to_string()))
+        })
+        .unwrap_or(Value::Null);
+    serde_json::json!({
+        "url": request.url().as_str(),
+        "headers": headers,
+        "request": body,
+    })
+}
+
+/// Wait requested by a `Retry-After` header, given in seconds or as an HTTP date, capped at
+/// [`MAX_RETRY_AFTER`]. A date in the past means no wait.
+fn retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    let wait = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(now)
+            .unwrap_or_default(),
+    };
+    Some(wait.min(MAX_RETRY_AFTER))
+}
+
+/// `wait` prolonged by up to [`RETRY_JITTER`], scaled by `random` between 0 and 1. Never shorter,
+/// so a `Retry-After` is still honored.
+fn jitter(wait: Duration, random: f64) -> Duration {
+    wait.mul_f64(1.0 + RETRY_JITTER * random.clamp(0.0, 1.0))
+}
+
+/// Spaces requests evenly to at most `requests_per_minute`, shared by all queries of an [`AI`]
+/// no matter how many are in flight. A token bucket holding a single token.
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    fn new(requests_per_minute: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(60) / requests_per_minute.max(1),
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Takes the next free slot at or after `now` and returns when it starts.
+    fn reserve(&self, now: Instant) -> Instant {
+        let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+        let slot = next.map_or(now, |next| next.max(now));
+        *next = Some(slot + self.interval);
+        slot
+    }
+
+    async fn wait(&self) {
+        let slot = self.reserve(Instant::now());
+        tokio::time::sleep_until(slot.into()).await;
     }
 }
 
 pub struct AI {
     chat_request_factory: ChatRequestFactory,
     client: reqwest::Client,
-    url: String,
+    /// Base URL of the endpoint followed by the fallback endpoints, see [`AI::with_fallback_urls`].
+    urls: Vec<String>,
+    /// Index into `urls` of the endpoint requests are sent to first.
+    current_url: AtomicUsize,
     auth_token: Option<String>,
+    auth_scheme: AuthScheme,
+    samples: usize,
+    sample_choices: bool,
+    /// Temperatures of the samples in turn, see [`AI::with_sample_temperatures`].
+    sample_temperatures: Vec<f32>,
+    request_log: Option<Mutex<Box<dyn Write + Send>>>,
+    reparse_retries: usize,
+    reparses: AtomicUsize,
+    rate_limiter: Option<RateLimiter>,
 }
 
 impl AI {
@@ -155,59 +727,697 @@ impl AI {
         let chat_request_factory =
             ChatRequestFactory::new(model.into(), temperature, ai_query_config, question.into());
         let client = reqwest::Client::new();
-        let url = url.into();
         Self {
             chat_request_factory,
             client,
-            url,
+            urls: vec![url.into()],
+            current_url: AtomicUsize::new(0),
             auth_token,
+            auth_scheme: AuthScheme::default(),
+            samples: 1,
+            sample_choices: false,
+            sample_temperatures: Vec::new(),
+            request_log: None,
+            reparse_retries: 0,
+            reparses: AtomicUsize::new(0),
+            rate_limiter: None,
         }
     }
 
-    pub async fn query(&self, code: impl AsRef<str>) -> anyhow::Result<f32> {
-        let chat_request = self.chat_request_factory.create_json(code.as_ref())?;
+    /// Sends all requests through `proxy` instead of the proxies of the environment like
+    /// `HTTPS_PROXY`, except to the hosts in `NO_PROXY`.
+    pub fn with_proxy(mut self, proxy: Option<&str>) -> crate::Result<Self> {
+        if proxy.is_some() {
+            self.client = client(proxy, reqwest::NoProxy::from_env())?;
+        }
+        Ok(self)
+    }
+
+    /// Endpoints to try in turn when a request can't be sent or fails with a server error, all
+    /// of them taking the same auth token.
+    pub fn with_fallback_urls(mut self, urls: impl IntoIterator<Item = String>) -> Self {
+        self.urls.extend(urls);
+        self
+    }
+
+    /// Sends at most `requests_per_minute` requests, including samples and reparses, across
+    /// all queries.
+    pub fn with_rate_limit(mut self, requests_per_minute: Option<u32>) -> Self {
+        self.rate_limiter = requests_per_minute.map(RateLimiter::new);
+        self
+    }
 
-        let url = reqwest::Url::parse(&format!("{}/chat/completions", self.url))?;
+    /// Requests a response up to `reparse_retries` more times with a reminder of the response
+    /// format if it can't be parsed.
+    pub fn with_reparse_retries(mut self, reparse_retries: usize) -> Self {
+        self.reparse_retries = reparse_retries;
+        self
+    }
 
-        let request = self
-            .client
-            .post(url)
-            .body(chat_request)
-            .header("Content-Type", "application/json");
-        let request = match &self.auth_token {
-            Some(auth_token) => request.bearer_auth(auth_token),
-            None => request,
+    /// Number of responses requested again because they couldn't be parsed.
+    pub fn reparses(&self) -> usize {
+        self.reparses.load(Ordering::Relaxed)
+    }
+
+    /// Whether a response that couldn't be parsed in `attempt` is requested again.
+    fn reparse(&self, attempt: usize) -> bool {
+        let reparse = attempt < self.reparse_retries;
+        if reparse {
+            self.reparses.fetch_add(1, Ordering::Relaxed);
+        }
+        reparse
+    }
+
+    /// Writes every request and its response as a JSON line to `request_log`, without the
+    /// auth token.
+    pub fn with_request_log(mut self, request_log: impl Write + Send + 'static) -> Self {
+        self.request_log = Some(Mutex::new(Box::new(request_log)));
+        self
+    }
+
+    fn log_request(&self, mut entry: Value, status: u16, response: &str) -> crate::Result<()> {
+        let Some(request_log) = &self.request_log else {
+            return Ok(());
         };
-        let request = request.build()?;
-
-        let response = self.client.execute(request).await?;
-        let response: Value = serde_json::from_str(&response.text().await?)?;
-        let response = response
-            .get("choices")
-            .ok_or(anyhow::anyhow!("No choices in response: {:?}", response))?;
-        let response = response
-            .get(0)
-            .ok_or(anyhow::anyhow!("No choice in response: {:?}", response))?;
-        let response = response
-            .get("message")
-            .ok_or(anyhow::anyhow!("No message in response: {:?}", response))?;
-        let response = response
-            .get("content")
-            .ok_or(anyhow::anyhow!("No content in response: {:?}", response))?;
-        let response = response.as_str().ok_or(anyhow::anyhow!(
-            "No string content in response: {:?}",
-            response
-        ))?;
+        entry["status"] = status.into();
+        // streamed responses aren't a single JSON document, they are logged as they came
+        entry["response"] =
+            serde_json::from_str(response).unwrap_or_else(|_| Value::String(response.to_string()));
+        let mut request_log = request_log
+            .lock()
+            .map_err(|_| std::io::Error::other("Request log poisoned"))?;
+        writeln!(request_log, "{}", entry)?;
+        request_log.flush()?;
+        Ok(())
+    }
+
+    pub fn with_auth_scheme(mut self, auth_scheme: AuthScheme) -> Self {
+        self.auth_scheme = auth_scheme;
+        self
+    }
+
+    /// Queries the model `samples` times per evaluation and aggregates the results.
+    pub fn with_samples(mut self, samples: usize) -> Self {
+        self.samples = samples.max(1);
+        self
+    }
+
+    pub fn question(&self) -> &str {
+        &self.chat_request_factory.question
+    }
+
+    /// Role and content of the messages of a request for `codes`, batched if there are several.
+    pub fn messages(&self, codes: &[impl AsRef<str>]) -> Vec<(String, String)> {
+        let chat_request = match codes {
+            [code] => self.chat_request_factory.create(code.as_ref()),
+            _ => self.chat_request_factory.create_batch(codes),
+        };
+        chat_request
+            .messages
+            .into_iter()
+            .map(|message| (message.role, message.content))
+            .collect()
+    }
+
+    /// Sends `context`, e.g. the content of all input files, with every request.
+    pub fn with_context(mut self, context: impl Into<String>) -> Self {
+        self.chat_request_factory.context = Some(context.into());
+        self
+    }
+
+    /// Changes the question for the following queries.
+    pub fn set_question(&mut self, question: impl Into<String>) {
+        self.chat_request_factory.question = question.into();
+    }
+
+    /// Requests all samples as choices of a single request using the `n` parameter. Endpoints
+    /// returning fewer choices than requested yield fewer samples. Not used when streaming.
+    pub fn with_sample_choices(mut self, sample_choices: bool) -> Self {
+        self.sample_choices = sample_choices;
+        self
+    }
+
+    /// Takes a sample at each of `temperatures` instead of all at the configured temperature, so
+    /// the samples differ. Sets the number of samples to the number of temperatures and takes
+    /// precedence over [`AI::with_sample_choices`], as all choices share a temperature.
+    pub fn with_sample_temperatures(mut self, temperatures: Vec<f32>) -> Self {
+        if !temperatures.is_empty() {
+            self.samples = temperatures.len();
+        }
+        self.sample_temperatures = temperatures;
+        self
+    }
+
+    /// Temperature of the request for the sample with index `sample`, if it differs from the
+    /// configured one.
+    fn sample_temperature(&self, sample: usize) -> Option<f32> {
+        self.sample_temperatures.get(sample).copied()
+    }
+
+    /// Number of choices to request per request, if all samples are taken in one request.
+    fn choices(&self) -> Option<usize> {
+        (self.sample_choices
+            && self.samples > 1
+            && self.sample_temperatures.is_empty()
+            && !self.chat_request_factory.stream)
+            .then_some(self.samples)
+    }
+
+    fn requests(&self) -> usize {
+        if self.choices().is_some() {
+            1
+        } else {
+            self.samples
+        }
+    }
+
+    /// Requests streamed responses, see [`AI::query_with_progress`].
+    pub fn with_stream(mut self, stream: bool) -> Self {
+        self.chat_request_factory.stream = stream;
+        self
+    }
+
+    pub async fn query(&self, code: impl AsRef<str>) -> crate::Result<QueryResult> {
+        self.query_with_progress(code, |_| {}).await
+    }
+
+    /// Like [`AI::query`], but when streaming, `on_progress` is called with the content received
+    /// so far whenever it grows.
+    pub async fn query_with_progress(
+        &self,
+        code: impl AsRef<str>,
+        mut on_progress: impl FnMut(&str),
+    ) -> crate::Result<QueryResult> {
+        let mut samples = Vec::with_capacity(self.samples);
+        for sample in 0..self.requests() {
+            // the usage of discarded responses is reported with the parsed ones
+            let mut usage = None;
+            for attempt in 0.. {
+                let chat_request = self.chat_request_factory.create_json(
+                    code.as_ref(),
+                    self.choices(),
+                    self.sample_temperature(sample),
+                    attempt > 0,
+                )?;
+                let (contents, response_usage) = self.send(chat_request, &mut on_progress).await?;
+                usage = add_usage(usage, response_usage);
+                let results = contents
+                    .iter()
+                    .map(|content| self.make_result(content, None))
+                    .collect::<crate::Result<Vec<_>>>();
+                match results {
+                    Ok(mut results) => {
+                        if let Some(first) = results.first_mut() {
+                            first.usage = usage;
+                        }
+                        samples.extend(results);
+                        break;
+                    }
+                    Err(Error::Parse(_)) if self.reparse(attempt) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        QueryResult::aggregate(samples)
+    }
 
-        self.chat_request_factory
+    /// Asks the model to explain in prose how the question applies to `code`, instead of scoring
+    /// it. Returns the explanation and the usage of the request.
+    pub async fn explain(&self, code: impl AsRef<str>) -> crate::Result<(String, Option<Usage>)> {
+        let chat_request = self
+            .chat_request_factory
+            .create_explain_json(code.as_ref())?;
+        let (contents, usage) = self.send(chat_request, |_| {}).await?;
+        let explanation = contents
+            .into_iter()
+            .next()
+            .ok_or(Error::Parse("No explanation in response".to_string()))?;
+        Ok((explanation, usage))
+    }
+
+    /// Evaluates all `codes` in a single request, the results are in the order of `codes`. The
+    /// usage of the request is reported with the first result.
+    pub async fn query_batch(&self, codes: &[impl AsRef<str>]) -> crate::Result<Vec<QueryResult>> {
+        self.query_batch_with_progress(codes, |_| {}).await
+    }
+
+    /// Like [`AI::query_batch`], with progress reported as in [`AI::query_with_progress`].
+    pub async fn query_batch_with_progress(
+        &self,
+        codes: &[impl AsRef<str>],
+        mut on_progress: impl FnMut(&str),
+    ) -> crate::Result<Vec<QueryResult>> {
+        let mut samples: Vec<Vec<QueryResult>> = (0..codes.len()).map(|_| Vec::new()).collect();
+        for sample in 0..self.requests() {
+            let mut usage = None;
+            for attempt in 0.. {
+                let chat_request = self.chat_request_factory.create_batch_json(
+                    codes,
+                    self.choices(),
+                    self.sample_temperature(sample),
+                    attempt > 0,
+                )?;
+                let (choices, response_usage) = self.send(chat_request, &mut on_progress).await?;
+                usage = add_usage(usage, response_usage);
+                let results = choices
+                    .iter()
+                    .map(|content| self.make_batch_results(content, codes.len()))
+                    .collect::<crate::Result<Vec<_>>>();
+                match results {
+                    Ok(results) => {
+                        for (idx, result) in results.into_iter().flatten().enumerate() {
+                            samples[idx % codes.len()].push(QueryResult {
+                                usage: usage.take(),
+                                ..result
+                            });
+                        }
+                        break;
+                    }
+                    Err(Error::Parse(_)) if self.reparse(attempt) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        samples.into_iter().map(QueryResult::aggregate).collect()
+    }
+
+    /// Results of a batch response with `len` results.
+    fn make_batch_results(&self, content: &str, len: usize) -> crate::Result<Vec<QueryResult>> {
+        let contents = self
+            .chat_request_factory
             .ai_query_config
-            .extract_result(response)
+            .split_batch(content)?;
+        if contents.len() != len {
+            return Err(Error::Parse(format!(
+                "Expected {} results in response, got {}: {}",
+                len,
+                contents.len(),
+                content
+            )));
+        }
+        contents
+            .iter()
+            .map(|content| self.make_result(content, None))
+            .collect()
+    }
+
+    /// Sends `chat_request` to the current endpoint, and if that can't be reached or answers
+    /// with a server error, to the following fallback endpoints in turn. The endpoint that
+    /// answered stays the current one for the following requests.
+    async fn send_with_failover(
+        &self,
+        chat_request: &str,
+    ) -> crate::Result<(reqwest::Response, Option<Value>)> {
+        let first = self.current_url.load(Ordering::Relaxed);
+        let mut attempt = 0;
+        loop {
+            let idx = (first + attempt) % self.urls.len();
+            let last = attempt + 1 == self.urls.len();
+            match self.send_to(&self.urls[idx], chat_request).await {
+                Ok((response, _)) if !last && response.status().is_server_error() => {}
+                Err(_) if !last => {}
+                result => {
+                    if result.is_ok() {
+                        self.current_url.store(idx, Ordering::Relaxed);
+                    }
+                    return result;
+                }
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Sends `chat_request` to the endpoint at `url`, again after a wait while it is rate
+    /// limited. Returns the response and the request log entry, if logging.
+    async fn send_to(
+        &self,
+        url: &str,
+        chat_request: &str,
+    ) -> crate::Result<(reqwest::Response, Option<Value>)> {
+        let url = endpoint_url(url, "chat/completions")?;
+
+        // built again for each attempt, as a sent request is consumed
+        let build_request = || {
+            let request = self
+                .client
+                .post(url.clone())
+                .body(chat_request.to_string())
+                .header("Content-Type", "application/json");
+            with_auth(request, &self.auth_token, self.auth_scheme).build()
+        };
+        let log_entry = match &self.request_log {
+            Some(_) => Some(request_log_entry(&build_request()?)),
+            None => None,
+        };
+
+        let mut retries = 0;
+        let response = loop {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.wait().await;
+            }
+            let response = self.client.execute(build_request()?).await?;
+            if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                break response;
+            }
+            if retries == RATE_LIMITED_RETRIES {
+                // fails for the 429
+                break response.error_for_status()?;
+            }
+            let wait = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| retry_after(value, SystemTime::now()))
+                .unwrap_or(RATE_LIMITED_BACKOFF * 2u32.pow(retries));
+            retries += 1;
+            tokio::time::sleep(jitter(wait, fastrand::f64())).await;
+        };
+        Ok((response, log_entry))
+    }
+
+    /// Sends `chat_request` and returns the content of each choice and the usage of the response.
+    async fn send(
+        &self,
+        chat_request: String,
+        mut on_progress: impl FnMut(&str),
+    ) -> crate::Result<(Vec<String>, Option<Usage>)> {
+        let (response, log_entry) = self.send_with_failover(&chat_request).await?;
+        let status = response.status().as_u16();
+        let mut response = match check_auth(response).await {
+            Err(Error::Auth { status, body }) => {
+                if let Some(log_entry) = log_entry {
+                    self.log_request(log_entry, status, &body)?;
+                }
+                return Err(Error::Auth { status, body });
+            }
+            response => response?,
+        };
+        if !response.status().is_success() {
+            let body = response.text().await?;
+            if let Some(log_entry) = log_entry {
+                self.log_request(log_entry, status, &body)?;
+            }
+            return Err(Error::Status { status, body });
+        }
+
+        if self.chat_request_factory.stream {
+            let mut stream = StreamAccumulator::default();
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                if log_entry.is_some() {
+                    body.extend_from_slice(&chunk);
+                }
+                if stream.push(&chunk)? {
+                    on_progress(&stream.content);
+                }
+                if stream.done {
+                    break;
+                }
+            }
+            if !stream.done && stream.finish()? {
+                on_progress(&stream.content);
+            }
+            if let Some(log_entry) = log_entry {
+                self.log_request(log_entry, status, &String::from_utf8_lossy(&body))?;
+            }
+            if stream.content.is_empty() && !stream.refusal.is_empty() {
+                return Err(Error::Refusal(stream.refusal));
+            }
+            return Ok((vec![stream.content], stream.usage));
+        }
+
+        let text = response.text().await?;
+        if let Some(log_entry) = log_entry {
+            self.log_request(log_entry, status, &text)?;
+        }
+        let response: Value = serde_json::from_str(&text)?;
+        let usage = Usage::from_response(&response);
+        Ok((choice_contents(&response)?, usage))
+    }
+
+    fn make_result(&self, content: &str, usage: Option<Usage>) -> crate::Result<QueryResult> {
+        let ai_query_config = &self.chat_request_factory.ai_query_config;
+        Ok(QueryResult {
+            value: ai_query_config.extract_result(content)?,
+            reason: ai_query_config.extract_reason(content),
+            usage,
+            std_dev: None,
+            raw: content.to_string(),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{AiQueryConfig, DefaultAiQueryConfig};
+    use super::{
+        AI, AiQueryConfig, ChatRequestFactory, DefaultAiQueryConfig, EXPLAIN_PROMPT, Error,
+        MAX_RETRY_AFTER, QueryResult, REPARSE_REMINDER, RateLimiter, SchemaAiQueryConfig,
+        StreamAccumulator, Usage, choice_contents, client, endpoint_url, jitter, model_ids,
+        request_log_entry, retry_after,
+    };
+    use std::time::{Duration, Instant, SystemTime};
+
+    #[test]
+    fn messages_show_single_and_batched_requests() {
+        let ai = AI::new("m", "u", None, None, DefaultAiQueryConfig, "Q?");
+        let messages = ai.messages(&["{code}"]);
+        assert_eq!(messages[0].0, "system");
+        assert!(messages[0].1.ends_with("Question: Q?"));
+        assert_eq!(messages[1], ("user".to_string(), "{code}".to_string()));
+
+        let messages = ai.messages(&["{code}", "{code}"]);
+        assert_eq!(
+            messages[1].1,
+            "### Fragment 1\n{code}\n\n### Fragment 2\n{code}"
+        );
+    }
+
+    /// Answers a single request on `listener` with a chat completion of `content`, gzipped if
+    /// `gzip` is set. The thread returns the header lines of the request.
+    fn serve_once(
+        listener: std::net::TcpListener,
+        content: &'static str,
+        gzip: bool,
+    ) -> std::thread::JoinHandle<std::io::Result<String>> {
+        use std::io::Write;
+        let mut body = serde_json::json!({"choices": [{"message": {"content": content}}]})
+            .to_string()
+            .into_bytes();
+        let mut headers = "Content-Type: application/json\r\n".to_string();
+        if gzip {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&body).expect("Compression expected");
+            body = encoder.finish().expect("Compression expected");
+            headers.push_str("Content-Encoding: gzip\r\n");
+        }
+        serve_response(listener, "200 OK", headers, body)
+    }
+
+    /// Answers a single request on `listener` with `status`, the `headers` lines and `body`. The
+    /// thread returns the header lines of the request.
+    fn serve_response(
+        listener: std::net::TcpListener,
+        status: &'static str,
+        headers: String,
+        body: Vec<u8>,
+    ) -> std::thread::JoinHandle<std::io::Result<String>> {
+        use std::io::{BufRead, BufReader, Read, Write};
+        std::thread::spawn(move || -> std::io::Result<String> {
+            let (stream, _) = listener.accept()?;
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                if line.trim().is_empty() {
+                    break;
+                }
+                head.push_str(&line);
+                if let Some((name, value)) = line.split_once(':')
+                    && name.eq_ignore_ascii_case("content-length")
+                {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+            reader.read_exact(&mut vec![0; content_length])?;
+            let stream = reader.get_mut();
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                headers,
+                body.len()
+            )?;
+            stream.write_all(&body)?;
+            Ok(head)
+        })
+    }
+
+    #[tokio::test]
+    async fn stream_error_status_is_reported() -> anyhow::Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/v1", listener.local_addr()?);
+        let body = b"data: {\"error\":\"overloaded\"}\n".to_vec();
+        let headers = "Content-Type: text/event-stream\r\n".to_string();
+        serve_response(listener, "503 Service Unavailable", headers, body);
+
+        let ai = AI::new("m", url, None, None, DefaultAiQueryConfig, "Q?").with_stream(true);
+        let error = ai.query("fn one() {}").await;
+        assert!(matches!(error, Err(Error::Status { status: 503, .. })));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_usage_is_requested_and_collected() -> anyhow::Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/v1", listener.local_addr()?);
+        let body = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"{\\\"score\\\":0.5}\"}}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":10,\"completion_tokens\":3}}\n\n",
+            "data: [DONE]\n\n"
+        );
+        let headers = "Content-Type: text/event-stream\r\n".to_string();
+        let server = serve_response(listener, "200 OK", headers, body.as_bytes().to_vec());
+
+        let ai = AI::new("m", url, None, None, DefaultAiQueryConfig, "Q?").with_stream(true);
+        let result = ai.query("fn one() {}").await?;
+        assert_eq!(result.value, 0.5);
+        assert_eq!(
+            result.usage,
+            Some(Usage {
+                prompt_tokens: 10,
+                completion_tokens: 3
+            })
+        );
+        let request = ai
+            .chat_request_factory
+            .create_json("fn one() {}", None, None, false)?;
+        let request: serde_json::Value = serde_json::from_str(&request)?;
+        assert_eq!(request["stream_options"]["include_usage"], true);
+        server.join().expect("Server thread expected")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compressed_responses_are_negotiated_and_decoded() -> anyhow::Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/v1", listener.local_addr()?);
+        let server = serve_once(listener, r#"{"score":0.5}"#, true);
+
+        let ai = AI::new("m", url, None, None, DefaultAiQueryConfig, "Q?");
+        assert_eq!(ai.query("fn one() {}").await?.value, 0.5);
+
+        let head = server.join().expect("server thread panicked")?;
+        let accept_encoding = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("accept-encoding")
+                    .then_some(value)
+            })
+            .unwrap_or_default();
+        for encoding in ["gzip", "deflate", "br"] {
+            assert!(accept_encoding.contains(encoding), "{}", accept_encoding);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_is_used_except_for_no_proxy_hosts() -> anyhow::Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let proxy = format!("http://{}", listener.local_addr()?);
+        let server = serve_once(listener, r#"{"score":0.5}"#, false);
+
+        let mut ai = AI::new(
+            "m",
+            "http://grepowski.invalid/v1",
+            None,
+            None,
+            DefaultAiQueryConfig,
+            "Q?",
+        );
+        ai.client = client(Some(&proxy), None)?;
+        assert_eq!(ai.query("fn one() {}").await?.value, 0.5);
+        let head = server.join().expect("server thread panicked")?;
+        assert!(head.starts_with("POST http://grepowski.invalid/v1/chat/completions "));
+
+        // nothing listens on the port of a dropped listener
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/v1", listener.local_addr()?);
+        serve_once(listener, r#"{"score":0.75}"#, false);
+
+        let mut ai = AI::new("m", url, None, None, DefaultAiQueryConfig, "Q?");
+        ai.client = client(
+            Some(&format!("http://{}", unreachable)),
+            reqwest::NoProxy::from_string("127.0.0.1"),
+        )?;
+        assert_eq!(ai.query("fn one() {}").await?.value, 0.75);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unreachable_url_fails_over_to_the_next() -> anyhow::Result<()> {
+        // nothing listens on the port of a dropped listener
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let fallback = listener.local_addr()?;
+        serve_once(listener, r#"{"score":0.25}"#, false);
+
+        let ai = AI::new(
+            "m",
+            format!("http://{}/v1", unreachable),
+            None,
+            None,
+            DefaultAiQueryConfig,
+            "Q?",
+        )
+        .with_fallback_urls([format!("http://{}/v1", fallback)]);
+
+        assert_eq!(ai.query("fn one() {}").await?.value, 0.25);
+        assert_eq!(ai.current_url.load(std::sync::atomic::Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn jitter_only_prolongs_waits() {
+        let wait = Duration::from_secs(2);
+        assert_eq!(jitter(wait, 0.0), wait);
+        assert_eq!(jitter(wait, 0.5), Duration::from_millis(2500));
+        assert_eq!(jitter(wait, 1.0), Duration::from_secs(3));
+        assert_eq!(jitter(wait, 7.0), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn retry_after_parses_seconds_and_dates() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(retry_after(" 120 ", now), Some(Duration::from_secs(120)));
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after("86400", now), Some(MAX_RETRY_AFTER));
+        assert_eq!(retry_after("soon", SystemTime::UNIX_EPOCH), None);
+    }
+
+    #[test]
+    fn rate_limiter_spaces_reserved_slots() {
+        let limiter = RateLimiter::new(120);
+        let start = Instant::now();
+        assert_eq!(limiter.reserve(start), start);
+        assert_eq!(limiter.reserve(start), start + Duration::from_millis(500));
+        assert_eq!(limiter.reserve(start), start + Duration::from_secs(1));
+        // an idle limiter doesn't accumulate slots
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.reserve(later), later);
+        assert_eq!(limiter.reserve(later), later + Duration::from_millis(500));
+    }
 
     #[test]
     fn extract_result_parses_score() {
@@ -216,5 +1426,260 @@ mod tests {
             .extract_result(r#"{"score":0.42}"#)
             .expect("score parsed");
         assert!((score - 0.42).abs() < f32::EPSILON);
+        assert_eq!(
+            config.extract_reason(r#"{"reason":"allocates","score":0.42}"#),
+            Some("allocates".to_string())
+        );
+    }
+
+    #[test]
+    fn usage_parsed_from_response() {
+        let response = serde_json::json!({
+            "usage": {"prompt_tokens": 120, "completion_tokens": 30, "total_tokens": 150}
+        });
+        let usage = Usage::from_response(&response).expect("usage parsed");
+        assert_eq!(usage.total_tokens(), 150);
+        assert_eq!(Usage::from_response(&serde_json::json!({})), None);
+    }
+
+    #[test]
+    fn stream_accumulator_collects_split_events() -> anyhow::Result<()> {
+        let mut stream = StreamAccumulator::default();
+        assert!(!stream.push(b"data: {\"choices\":[{\"delta\":{\"content\":\"{\\\"sco")?);
+        assert!(stream.push(
+            b"re\\\":\"}}]}\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"0.5}\"}}]}\n"
+        )?);
+        stream.push(b"data: [DONE]\n")?;
+        assert!(stream.done);
+        assert_eq!(stream.content, r#"{"score":0.5}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn stream_accumulator_takes_a_last_event_without_line_break() -> anyhow::Result<()> {
+        let mut stream = StreamAccumulator::default();
+        assert!(!stream.push(b"data: {\"choices\":[{\"delta\":{\"content\":\"done\"}}]}")?);
+        assert!(stream.finish()?);
+        assert_eq!(stream.content, "done");
+        assert!(!stream.finish()?);
+        Ok(())
+    }
+
+    #[test]
+    fn model_ids_parsed_from_response() -> anyhow::Result<()> {
+        let response = serde_json::json!({
+            "object": "list",
+            "data": [{"id": "qwen", "object": "model"}, {"id": "llama", "object": "model"}]
+        });
+        assert_eq!(model_ids(&response)?, ["qwen", "llama"]);
+        assert!(model_ids(&serde_json::json!({})).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn batch_request_numbers_fragments_and_results_split() -> anyhow::Result<()> {
+        let factory = ChatRequestFactory::new(
+            "model".to_string(),
+            None,
+            DefaultAiQueryConfig,
+            "q".to_string(),
+        );
+        let request = factory.create_batch(&["fn a() {}", "fn b() {}"]);
+        assert_eq!(
+            request.messages[1].content,
+            "### Fragment 1\nfn a() {}\n\n### Fragment 2\nfn b() {}"
+        );
+        assert_eq!(
+            request.response_format["json_schema"]["schema"]["properties"]["results"]["items"]["required"],
+            serde_json::json!(["reason", "score"])
+        );
+
+        let config = DefaultAiQueryConfig;
+        let contents = config.split_batch(
+            r#"{"results":[{"reason":"a","score":0.1},{"reason":"b","score":0.9}]}"#,
+        )?;
+        assert_eq!(contents.len(), 2);
+        assert!((config.extract_result(&contents[1])? - 0.9).abs() < f32::EPSILON);
+        assert_eq!(config.extract_reason(&contents[0]), Some("a".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn sample_temperatures_override_the_configured_one() -> anyhow::Result<()> {
+        let factory = ChatRequestFactory::new(
+            "model".to_string(),
+            Some(0.0),
+            DefaultAiQueryConfig,
+            "q".to_string(),
+        );
+        let request: serde_json::Value =
+            serde_json::from_str(&factory.create_json("fn a() {}", None, Some(0.5), false)?)?;
+        assert_eq!(request["temperature"], 0.5);
+        let request: serde_json::Value =
+            serde_json::from_str(&factory.create_json("fn a() {}", None, None, false)?)?;
+        assert_eq!(request["temperature"], 0.0);
+
+        let ai = AI::new("m", "u", None, Some(0.0), DefaultAiQueryConfig, "q")
+            .with_sample_choices(true)
+            .with_sample_temperatures(vec![0.0, 0.5, 1.0]);
+        assert_eq!(ai.requests(), 3);
+        assert_eq!(ai.choices(), None);
+        assert_eq!(ai.sample_temperature(2), Some(1.0));
+        Ok(())
+    }
+
+    #[test]
+    fn explain_request_has_no_response_format() -> anyhow::Result<()> {
+        let factory = ChatRequestFactory::new(
+            "model".to_string(),
+            None,
+            DefaultAiQueryConfig,
+            "q".to_string(),
+        );
+        let request: serde_json::Value =
+            serde_json::from_str(&factory.create_explain_json("fn a() {}")?)?;
+        assert!(request.get("response_format").is_none());
+        assert_eq!(
+            request["messages"][0]["content"],
+            format!("{} Question: q", EXPLAIN_PROMPT)
+        );
+        assert_eq!(request["messages"][1]["content"], "fn a() {}");
+
+        let request: serde_json::Value =
+            serde_json::from_str(&factory.create_json("fn a() {}", None, None, false)?)?;
+        assert!(request.get("response_format").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn reparse_request_ends_with_reminder() -> anyhow::Result<()> {
+        let factory = ChatRequestFactory::new(
+            "model".to_string(),
+            None,
+            DefaultAiQueryConfig,
+            "q".to_string(),
+        );
+        let request: serde_json::Value =
+            serde_json::from_str(&factory.create_json("fn a() {}", None, None, false)?)?;
+        assert_eq!(request["messages"].as_array().map(Vec::len), Some(2));
+
+        let request: serde_json::Value =
+            serde_json::from_str(&factory.create_json("fn a() {}", None, None, true)?)?;
+        assert_eq!(request["messages"][1]["content"], "fn a() {}");
+        assert_eq!(request["messages"][2]["role"], "user");
+        assert_eq!(request["messages"][2]["content"], REPARSE_REMINDER);
+        Ok(())
+    }
+
+    #[test]
+    fn samples_aggregate_to_mean_and_std_dev() -> anyhow::Result<()> {
+        let sample = |value, usage| QueryResult {
+            value,
+            reason: Some(format!("{}", value)),
+            usage,
+            std_dev: None,
+            raw: format!("{}", value),
+        };
+        let usage = Usage {
+            prompt_tokens: 10,
+            completion_tokens: 5,
+        };
+        let result =
+            QueryResult::aggregate(vec![sample(0.2, Some(usage)), sample(0.6, Some(usage))])?;
+        assert!((result.value - 0.4).abs() < 1e-6);
+        assert!((result.std_dev.expect("std dev") - 0.2).abs() < 1e-6);
+        assert_eq!(result.reason, Some("0.2".to_string()));
+        assert_eq!(result.raw, "0.2");
+        assert_eq!(result.usage.map(|usage| usage.total_tokens()), Some(30));
+
+        let single = QueryResult::aggregate(vec![sample(0.7, None)])?;
+        assert_eq!(single.std_dev, None);
+        Ok(())
+    }
+
+    #[test]
+    fn custom_schema_requires_score() -> anyhow::Result<()> {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "score": { "type": "number" },
+                "confidence": { "type": "number" }
+            },
+            "required": ["score", "confidence"]
+        });
+        let config = SchemaAiQueryConfig::new(schema.clone())?;
+        assert_eq!(config.response_format()["json_schema"]["schema"], schema);
+        assert!(matches!(
+            SchemaAiQueryConfig::new(serde_json::json!({"properties": {}})),
+            Err(Error::Parse(_))
+        ));
+        assert!(SchemaAiQueryConfig::new(serde_json::json!([])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_endpoint_url_is_reported() -> anyhow::Result<()> {
+        assert_eq!(
+            endpoint_url("http://localhost:8080/v1", "models")?.as_str(),
+            "http://localhost:8080/v1/models"
+        );
+        assert!(matches!(
+            endpoint_url("not a url", "models"),
+            Err(Error::Url(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn choice_contents_returns_all_choices() -> anyhow::Result<()> {
+        let response = serde_json::json!({
+            "choices": [
+                { "index": 0, "message": { "content": "{\"score\":0.1}" } },
+                { "index": 1, "message": { "content": "{\"score\":0.3}" } }
+            ]
+        });
+        assert_eq!(
+            choice_contents(&response)?,
+            vec![r#"{"score":0.1}"#, r#"{"score":0.3}"#]
+        );
+        assert!(choice_contents(&serde_json::json!({"choices": []})).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn refusal_is_reported() {
+        let response = serde_json::json!({
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "refusal": "I can't help with that."
+                },
+                "finish_reason": "stop"
+            }]
+        });
+        let error = choice_contents(&response).expect_err("refusal expected");
+        assert_eq!(error.to_string(), "Model refused: I can't help with that.");
+        assert!(matches!(error, Error::Refusal(_)));
+        assert!(matches!(
+            DefaultAiQueryConfig.extract_result(r#"{"reason":"none"}"#),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn request_log_redacts_auth() -> anyhow::Result<()> {
+        let request = reqwest::Client::new()
+            .post("http://localhost/v1/chat/completions")
+            .bearer_auth("secret")
+            .header("x-api-key", "secret")
+            .body(r#"{"model":"m"}"#)
+            .build()?;
+        let entry = request_log_entry(&request);
+        assert!(!entry.to_string().contains("secret"));
+        assert_eq!(entry["headers"]["authorization"], "<redacted>");
+        assert_eq!(entry["request"]["model"], "m");
+        Ok(())
     }
 }
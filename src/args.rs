@@ -1,24 +1,353 @@
-use clap::{Args as ClapArgs, Parser, Subcommand};
+use clap::{Args as ClapArgs, CommandFactory, Parser, Subcommand};
 use clap_complete::Shell;
+use grepowski::{ai_query, fragment_evaluation};
+use std::{
+    ffi::{OsStr, OsString},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Command,
+
+    #[clap(
+        long,
+        global = true,
+        value_name = "PATH",
+        env = "GREPOWSKI_ENV_FILE",
+        help = "Load environment variables like GREPOWSKI_MODEL from this file, variables already set and flags take precedence",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub env_file: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
     #[command(about = "Ask a question to the configured model")]
-    Ask(AskArgs),
+    Ask(Box<AskArgs>),
+    #[command(about = "List the models available at the configured endpoint")]
+    Models(ModelsArgs),
+    #[command(about = "Measure the throughput and latency of the configured endpoint")]
+    Bench(BenchArgs),
+    #[command(about = "Check that a score can be parsed from the configured model's response")]
+    Validate(ValidateArgs),
     #[command(about = "Generate shell completions")]
     Completions {
         #[clap(value_enum, help = "Shell to generate completions for")]
         shell: Shell,
+        #[clap(
+            long,
+            value_name = "PATH",
+            help = "Write the completions to this file instead of stdout, creating missing directories",
+            value_hint = clap::ValueHint::FilePath
+        )]
+        out: Option<String>,
     },
 }
 
+#[derive(ClapArgs, Debug)]
+pub struct ModelsArgs {
+    #[clap(flatten)]
+    pub endpoint: EndpointArgs,
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct BenchArgs {
+    #[clap(
+        short,
+        long,
+        value_name = "MODEL",
+        env = "GREPOWSKI_MODEL",
+        help = "Model to use for the chat completion"
+    )]
+    pub model: String,
+
+    #[clap(
+        long,
+        value_name = "TEMPERATURE",
+        env = "GREPOWSKI_TEMPERATURE",
+        help = "Temperature for the chat completion"
+    )]
+    pub temperature: Option<f32>,
+
+    #[clap(flatten)]
+    pub endpoint: EndpointArgs,
+
+    #[clap(
+        short = 'n',
+        long,
+        value_name = "REQUESTS",
+        default_value = "20",
+        help = "Number of requests to send"
+    )]
+    pub requests: usize,
+
+    #[clap(
+        short = 'j',
+        long,
+        value_name = "REQUESTS",
+        default_value = "1",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Number of requests in flight at the same time"
+    )]
+    pub concurrency: usize,
+
+    #[clap(
+        long,
+        value_name = "REQUESTS_PER_MINUTE",
+        env = "GREPOWSKI_RATE_LIMIT",
+        value_parser = clap::builder::RangedU64ValueParser::<u32>::new().range(1..),
+        help = "Send at most this many requests per minute, evenly spaced"
+    )]
+    pub rate_limit: Option<u32>,
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct ValidateArgs {
+    #[clap(
+        short,
+        long,
+        value_name = "MODEL",
+        env = "GREPOWSKI_MODEL",
+        help = "Model to use for the chat completion"
+    )]
+    pub model: String,
+
+    #[clap(
+        long,
+        value_name = "TEMPERATURE",
+        env = "GREPOWSKI_TEMPERATURE",
+        help = "Temperature for the chat completion"
+    )]
+    pub temperature: Option<f32>,
+
+    #[clap(flatten)]
+    pub endpoint: EndpointArgs,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        env = "GREPOWSKI_JSON_SCHEMA_FILE",
+        help = "JSON schema of the model response, it must have a numeric score property",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub json_schema_file: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "QUESTION",
+        help = "Question to ask about the sample fragment instead of the built-in one"
+    )]
+    pub question: Option<String>,
+}
+
+const DEFAULT_URL: &str = "http://127.0.0.1:8080/v1";
+
+/// Where files are split into fragments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FragmentBy {
+    /// Blocks of lines, or bytes or tokens if given
+    Lines,
+    /// Each top-level function or class definition, split further by size where it is larger,
+    /// falling back to lines if none is detected
+    Symbol,
+}
+
+/// Format of the output file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// A JSON line per evaluation, written as soon as it is done
+    Jsonl,
+    /// A page with the highlighted code of the fragments by score, written at the end
+    Html,
+}
+
+/// What the results view lists first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Granularity {
+    /// Each fragment with its score
+    Fragment,
+    /// Each file with the best score of its fragments, expandable to the fragments
+    File,
+}
+
+/// How the auth token is sent to the endpoint, see [`ai_query::AuthScheme`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AuthScheme {
+    /// `Authorization: Bearer <token>`
+    Bearer,
+    /// `x-api-key: <token>`
+    XApiKey,
+    /// `Authorization: <token>`
+    Raw,
+}
+
+impl From<AuthScheme> for ai_query::AuthScheme {
+    fn from(value: AuthScheme) -> Self {
+        match value {
+            AuthScheme::Bearer => Self::Bearer,
+            AuthScheme::XApiKey => Self::XApiKey,
+            AuthScheme::Raw => Self::Raw,
+        }
+    }
+}
+
+/// Order of the results, see [`fragment_evaluation::SortBy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortBy {
+    /// By descending value
+    Score,
+    /// By file path and line
+    Location,
+}
+
+impl From<SortBy> for fragment_evaluation::SortBy {
+    fn from(value: SortBy) -> Self {
+        match value {
+            SortBy::Score => Self::Score,
+            SortBy::Location => Self::Location,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortOrder {
+    Desc,
+    Asc,
+}
+
+impl From<SortOrder> for fragment_evaluation::SortOrder {
+    fn from(value: SortOrder) -> Self {
+        match value {
+            SortOrder::Desc => Self::Desc,
+            SortOrder::Asc => Self::Asc,
+        }
+    }
+}
+
+/// Rescaling of the values, see [`fragment_evaluation::Normalize`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Normalize {
+    /// Rescale linearly so the lowest value is 0 and the highest is 1
+    MinMax,
+    /// Map the mean to 0.5 and three standard deviations either way to 0 and 1
+    ZScore,
+}
+
+impl From<Normalize> for fragment_evaluation::Normalize {
+    fn from(value: Normalize) -> Self {
+        match value {
+            Normalize::MinMax => Self::MinMax,
+            Normalize::ZScore => Self::ZScore,
+        }
+    }
+}
+
+/// Known hosted providers with an OpenAI compatible API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProviderPreset {
+    Openai,
+    Openrouter,
+    Groq,
+    Together,
+}
+
+impl ProviderPreset {
+    fn url(self) -> &'static str {
+        match self {
+            ProviderPreset::Openai => "https://api.openai.com/v1",
+            ProviderPreset::Openrouter => "https://openrouter.ai/api/v1",
+            ProviderPreset::Groq => "https://api.groq.com/openai/v1",
+            ProviderPreset::Together => "https://api.together.xyz/v1",
+        }
+    }
+
+    /// Environment variable the provider's documentation uses for the API key.
+    fn auth_token_env(self) -> &'static str {
+        match self {
+            ProviderPreset::Openai => "OPENAI_API_KEY",
+            ProviderPreset::Openrouter => "OPENROUTER_API_KEY",
+            ProviderPreset::Groq => "GROQ_API_KEY",
+            ProviderPreset::Together => "TOGETHER_API_KEY",
+        }
+    }
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct EndpointArgs {
+    #[clap(
+        long,
+        value_enum,
+        value_name = "PROVIDER",
+        env = "GREPOWSKI_PROVIDER_PRESET",
+        help = "Use the base URL and API key variable of a known provider"
+    )]
+    pub provider_preset: Option<ProviderPreset>,
+
+    #[clap(
+        short,
+        long,
+        value_name = "URL",
+        env = "GREPOWSKI_URL",
+        value_delimiter = ',',
+        help = "URL of the chat completion endpoint, overrides the provider preset, repeat it to fail over to the next one when a request fails [default: http://127.0.0.1:8080/v1]",
+        value_hint = clap::ValueHint::Url,
+    )]
+    pub url: Vec<String>,
+
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "GREPOWSKI_AUTH_TOKEN",
+        hide_env_values = true,
+        help = "Token for the chat completion endpoint, defaults to the provider preset's API key variable - if not set, the model will be used anonymously"
+    )]
+    pub auth_token: Option<String>,
+
+    #[clap(
+        long,
+        value_enum,
+        value_name = "SCHEME",
+        env = "GREPOWSKI_AUTH_SCHEME",
+        default_value = "bearer",
+        help = "How the auth token is sent to the endpoint"
+    )]
+    pub auth_scheme: AuthScheme,
+
+    #[clap(
+        long,
+        value_name = "URL",
+        env = "GREPOWSKI_PROXY",
+        help = "Send all requests through this proxy instead of the one from HTTP_PROXY / HTTPS_PROXY, hosts in NO_PROXY are still reached directly"
+    )]
+    pub proxy: Option<String>,
+}
+
+impl EndpointArgs {
+    pub fn url(&self) -> String {
+        match (self.url.first(), self.provider_preset) {
+            (Some(url), _) => url.clone(),
+            (None, Some(preset)) => preset.url().to_string(),
+            (None, None) => DEFAULT_URL.to_string(),
+        }
+    }
+
+    /// The URLs after the first one, tried in turn when a request fails.
+    pub fn fallback_urls(&self) -> Vec<String> {
+        self.url.iter().skip(1).cloned().collect()
+    }
+
+    pub fn auth_token(&self) -> Option<String> {
+        self.auth_token.clone().or_else(|| {
+            self.provider_preset
+                .and_then(|preset| std::env::var(preset.auth_token_env()).ok())
+        })
+    }
+}
+
 #[derive(ClapArgs, Debug)]
 pub struct AskArgs {
     #[clap(
@@ -30,11 +359,178 @@ pub struct AskArgs {
     )]
     pub accessibility_mode: bool,
 
+    #[clap(
+        long,
+        help = "Prefix the results with ▲, ■ or ▼ for high, medium and low scores, default in accessibility mode",
+        env = "GREPOWSKI_SCORE_MARKERS",
+        default_value = "false"
+    )]
+    pub score_markers: bool,
+
+    #[clap(
+        long,
+        help = "Shift the color of the progress gauge from the theme's start to its end color as gathering progresses",
+        env = "GREPOWSKI_GAUGE_GRADIENT",
+        default_value = "false"
+    )]
+    pub gauge_gradient: bool,
+
+    #[clap(
+        long,
+        help = "Use distinct colors for keywords, strings, types and comments in the code pane",
+        env = "GREPOWSKI_SYNTAX_COLORS",
+        default_value = "false"
+    )]
+    pub syntax_colors: bool,
+
+    #[clap(
+        short,
+        long,
+        help = "List each skipped file in the summary at the end of the run",
+        env = "GREPOWSKI_VERBOSE",
+        default_value = "false"
+    )]
+    pub verbose: bool,
+
+    #[clap(
+        long,
+        help = "Only show the current location and progress while gathering, default for short terminals",
+        env = "GREPOWSKI_COMPACT",
+        default_value = "false"
+    )]
+    pub compact: bool,
+
+    #[clap(
+        long,
+        help = "Plot the whole value history downsampled to the chart width, can be toggled while gathering",
+        env = "GREPOWSKI_FULL_HISTORY",
+        default_value = "false"
+    )]
+    pub full_history: bool,
+
+    #[clap(
+        long,
+        help = "Show the gathering progress as a percentage in the terminal title",
+        env = "GREPOWSKI_TITLE_PROGRESS",
+        default_value = "false"
+    )]
+    pub title_progress: bool,
+
+    #[clap(
+        long,
+        help = "Require pressing q or y a second time to quit while gathering",
+        env = "GREPOWSKI_CONFIRM_QUIT",
+        default_value = "false"
+    )]
+    pub confirm_quit: bool,
+
+    #[clap(
+        long,
+        help = "Show the results full width without the code pane, can be toggled in the results view",
+        env = "GREPOWSKI_LIST_ONLY",
+        default_value = "false"
+    )]
+    pub list_only: bool,
+
+    #[clap(
+        long,
+        help = "Print the system and user message sent to the model, with placeholders like {code} for the fragment, and exit",
+        env = "GREPOWSKI_PRINT_PROMPT",
+        default_value = "false"
+    )]
+    pub print_prompt: bool,
+
+    #[clap(
+        long,
+        value_name = "CELLS",
+        default_value = "20",
+        env = "GREPOWSKI_EFFECT_WIDTH",
+        help = "Width of the border highlight sweep"
+    )]
+    pub effect_width: f32,
+
+    #[clap(
+        long,
+        value_name = "LIGHTNESS",
+        default_value = "50",
+        env = "GREPOWSKI_EFFECT_STRENGTH",
+        help = "Lightness added by the border highlight sweep"
+    )]
+    pub effect_strength: f32,
+
+    #[clap(
+        long,
+        value_name = "FACTOR",
+        default_value = "1",
+        value_parser = parse_positive,
+        env = "GREPOWSKI_EFFECT_SPEED",
+        help = "Speed factor of the animations and the pauses between them"
+    )]
+    pub effect_speed: f32,
+
+    #[clap(
+        long,
+        help = "Skip the animation at startup, the border sweep still runs",
+        env = "GREPOWSKI_NO_INTRO",
+        default_value = "false"
+    )]
+    pub no_intro: bool,
+
+    #[clap(
+        long,
+        help = "Leave fragments whose query failed out of the results instead of aborting",
+        env = "GREPOWSKI_CONTINUE_ON_ERROR",
+        default_value = "false"
+    )]
+    pub continue_on_error: bool,
+
+    #[clap(
+        long,
+        help = "Query the fragments file by file in line order, in the order the files first appear",
+        env = "GREPOWSKI_GATHER_BY_FILE",
+        default_value = "false"
+    )]
+    pub gather_by_file: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "gather_by_file",
+        help = "Query the fragments round-robin across files, so a large file doesn't delay the results of the others",
+        env = "GREPOWSKI_INTERLEAVE_FILES",
+        default_value = "false"
+    )]
+    pub interleave_files: bool,
+
+    #[clap(
+        long,
+        value_name = "FRAGMENTS",
+        help = "Abort before querying the model if there are more fragments than this, unless --yes is given",
+        env = "GREPOWSKI_MAX_FRAGMENTS"
+    )]
+    pub max_fragments: Option<usize>,
+
+    #[clap(
+        short,
+        long,
+        help = "Evaluate all fragments even if there are more than --max-fragments",
+        default_value = "false"
+    )]
+    pub yes: bool,
+
+    #[clap(
+        long,
+        value_name = "LINES",
+        help = "Skip files with more lines than this, e.g. generated code, they are listed with the skipped files",
+        env = "GREPOWSKI_MAX_LINES_PER_FILE"
+    )]
+    pub max_lines_per_file: Option<usize>,
+
     #[clap(
         short,
         long,
         default_value = "10",
         env = "GREPOWSKI_LINES_PER_BLOCK",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
         value_name = "LINES",
         help = "Number of lines per block"
     )]
@@ -45,11 +541,104 @@ pub struct AskArgs {
         long,
         default_value = "3",
         env = "GREPOWSKI_BLOCKS_PER_FRAGMENT",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
         value_name = "BLOCKS",
         help = "Number of blocks per fragment"
     )]
     pub blocks_per_fragment: usize,
 
+    #[clap(
+        long,
+        value_name = "BYTES",
+        env = "GREPOWSKI_FRAGMENT_BYTES",
+        conflicts_with = "fragment_tokens",
+        help = "Split files into fragments of whole lines of at most this many bytes instead of line based blocks"
+    )]
+    pub fragment_bytes: Option<usize>,
+
+    #[clap(
+        long,
+        value_name = "TOKENS",
+        env = "GREPOWSKI_FRAGMENT_TOKENS",
+        help = "Split files into fragments of whole lines of approximately this many tokens instead of line based blocks"
+    )]
+    pub fragment_tokens: Option<usize>,
+
+    #[clap(
+        long,
+        env = "GREPOWSKI_WHOLE_FILE",
+        help = "Evaluate each file as a single fragment, files larger than --fragment-bytes or --fragment-tokens are still split"
+    )]
+    pub whole_file: bool,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "lines",
+        env = "GREPOWSKI_FRAGMENT_BY",
+        help = "Start fragments at line windows or at each top-level function or class definition"
+    )]
+    pub fragment_by: FragmentBy,
+
+    #[clap(
+        long,
+        default_value = "0",
+        env = "GREPOWSKI_MIN_FRAGMENT_LINES",
+        value_name = "LINES",
+        help = "Drop fragments with fewer lines"
+    )]
+    pub min_fragment_lines: usize,
+
+    #[clap(
+        long,
+        value_name = "SUFFIX=SYNTAX",
+        value_parser = parse_key_value,
+        help = "Use the given syntax name or extension for files ending in .SUFFIX, can be repeated"
+    )]
+    pub syntax_map: Vec<(String, String)>,
+
+    #[clap(
+        long,
+        value_name = "DIR",
+        env = "GREPOWSKI_SYNTAX_DIR",
+        help = "Load additional .sublime-syntax definitions from this directory",
+        value_hint = clap::ValueHint::DirPath
+    )]
+    pub syntax_dir: Option<String>,
+
+    #[clap(
+        short,
+        long,
+        default_value = "0",
+        env = "GREPOWSKI_CONTEXT_LINES",
+        value_name = "LINES",
+        help = "Number of lines before and after each fragment sent to the model as context"
+    )]
+    pub context_lines: usize,
+
+    #[clap(
+        long,
+        env = "GREPOWSKI_INCLUDE_PATH",
+        default_value = "false",
+        help = "Tell the model the path and line range of each fragment"
+    )]
+    pub include_path: bool,
+
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        env = "GREPOWSKI_USER_TEMPLATE",
+        help = "Format each fragment sent to the model with {code}, {path} and {lines} placeholders"
+    )]
+    pub user_template: Option<String>,
+
+    #[clap(
+        long,
+        env = "GREPOWSKI_FLATTEN",
+        help = "Send all input files concatenated as context with every fragment, for questions across files"
+    )]
+    pub flatten: bool,
+
     #[clap(
         short,
         long,
@@ -68,35 +657,643 @@ pub struct AskArgs {
     )]
     pub temperature: Option<f32>,
 
+    #[clap(flatten)]
+    pub endpoint: EndpointArgs,
+
+    #[clap(
+        long,
+        env = "GREPOWSKI_STREAM",
+        default_value = "false",
+        help = "Stream the responses and show them while they arrive"
+    )]
+    pub stream: bool,
+
+    #[clap(
+        long,
+        value_name = "FRAGMENTS",
+        default_value = "1",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        env = "GREPOWSKI_BATCH_SIZE",
+        help = "Number of fragments evaluated in a single request"
+    )]
+    pub batch_size: usize,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        env = "GREPOWSKI_JSON_SCHEMA_FILE",
+        help = "JSON schema of the model response, it must have a numeric score property",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub json_schema_file: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        env = "GREPOWSKI_REQUEST_LOG",
+        help = "Write every request and response as a JSON line to this file, auth headers are redacted",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub request_log: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "SAMPLES",
+        default_value = "1",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        env = "GREPOWSKI_SAMPLES",
+        help = "Number of times each fragment is evaluated, the mean is used and the standard deviation shown"
+    )]
+    pub samples: usize,
+
+    #[clap(
+        long,
+        env = "GREPOWSKI_SAMPLE_CHOICES",
+        help = "Get all samples as choices of a single request using the n parameter, for endpoints supporting it"
+    )]
+    pub sample_choices: bool,
+
+    #[clap(
+        long,
+        value_name = "TEMPERATURES",
+        value_delimiter = ',',
+        conflicts_with_all = ["samples", "sample_choices"],
+        env = "GREPOWSKI_SAMPLE_TEMPERATURES",
+        help = "Evaluate each fragment once at each of these comma separated temperatures, aggregated like --samples"
+    )]
+    pub sample_temperatures: Vec<f32>,
+
+    #[clap(
+        long,
+        value_name = "RETRIES",
+        default_value = "0",
+        env = "GREPOWSKI_REPARSE_RETRIES",
+        help = "Query again with a reminder of the response format when no score can be parsed from a response"
+    )]
+    pub reparse_retries: usize,
+
+    #[clap(
+        long,
+        value_name = "REQUESTS_PER_MINUTE",
+        env = "GREPOWSKI_RATE_LIMIT",
+        value_parser = clap::builder::RangedU64ValueParser::<u32>::new().range(1..),
+        help = "Send at most this many requests per minute, evenly spaced"
+    )]
+    pub rate_limit: Option<u32>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "score",
+        env = "GREPOWSKI_SORT",
+        help = "Order of the results, can be toggled in the results view"
+    )]
+    pub sort: SortBy,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "fragment",
+        env = "GREPOWSKI_GRANULARITY",
+        help = "Rank fragments or whole files by their best fragment, file grouping can be toggled in the results view"
+    )]
+    pub granularity: Granularity,
+
+    #[clap(
+        long,
+        value_enum,
+        env = "GREPOWSKI_SORT_ORDER",
+        help = "Direction of the result order, defaults to desc for score and asc for location"
+    )]
+    pub sort_order: Option<SortOrder>,
+
+    #[clap(
+        long,
+        value_enum,
+        value_name = "METHOD",
+        env = "GREPOWSKI_NORMALIZE",
+        help = "Rescale the values of the results to span 0 to 1, the output file keeps the values of the model"
+    )]
+    pub normalize: Option<Normalize>,
+
+    #[clap(
+        long = "price-per-1k",
+        value_name = "PRICE",
+        env = "GREPOWSKI_PRICE_PER_1K",
+        help = "Price per 1000 tokens used to estimate the cost of the run"
+    )]
+    pub price_per_1k: Option<f64>,
+
     #[clap(
         short,
         long,
-        value_name = "URL",
-        env = "GREPOWSKI_URL",
-        default_value = "http://127.0.0.1:8080/v1",
-        help = "URL of the chat completion endpoint",
-        value_hint = clap::ValueHint::Url,
+        value_name = "PATH",
+        env = "GREPOWSKI_OUTPUT",
+        help = "Write each evaluation to this file as a JSON line as soon as it is done",
+        value_hint = clap::ValueHint::FilePath
     )]
-    pub url: String,
+    pub output: Option<String>,
 
     #[clap(
-        short = 't',
         long,
-        value_name = "TOKEN",
-        env = "GREPOWSKI_AUTH_TOKEN",
-        hide_env_values = true,
-        help = "Bearer token for the chat completion endpoint - if not set, the model will be used anonymously"
+        requires = "output",
+        env = "GREPOWSKI_SORT_OUTPUT",
+        help = "Rewrite the output file sorted by score once all fragments are evaluated"
     )]
-    pub auth_token: Option<String>,
+    pub sort_output: bool,
 
-    #[clap(value_name = "QUESTION", help = "Question to ask the model")]
-    pub question: String,
+    #[clap(
+        long,
+        value_enum,
+        requires = "output",
+        default_value = "jsonl",
+        env = "GREPOWSKI_EXPORT_FORMAT",
+        help = "Write the output file as JSON lines or as an HTML page with highlighted code once all fragments are evaluated"
+    )]
+    pub export_format: ExportFormat,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        env = "GREPOWSKI_CHART_OUT",
+        help = "Write the value history chart as an SVG image to this file once all fragments are evaluated",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub chart_out: Option<String>,
+
+    #[clap(
+        value_name = "QUESTION",
+        help = "Question to ask the model, if neither this nor --question-file is given, $EDITOR is opened to write it"
+    )]
+    pub question: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        env = "GREPOWSKI_QUESTION_FILE",
+        help = "Read the question from a file, all positional arguments are then input files",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub question_file: Option<String>,
 
-    #[clap(value_name = "FILES", required = true, help = "Input files to analyze", value_hint = clap::ValueHint::FilePath
+    #[clap(
+        long,
+        value_name = "PATH",
+        env = "GREPOWSKI_FILES_FROM",
+        help = "Read additional input files from a manifest with one path per line, '-' reads from stdin",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub files_from: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        env = "GREPOWSKI_DIFF",
+        conflicts_with_all = ["files", "files_from"],
+        help = "Only evaluate the lines added by a unified diff, '-' reads from stdin",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub diff: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        env = "GREPOWSKI_FRAGMENTS_JSONL",
+        conflicts_with_all = ["files", "files_from", "diff", "watch"],
+        help = "Evaluate fragments chunked elsewhere, JSON lines with path, first_line (zero based) and content, '-' reads from stdin",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub fragments_jsonl: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "DURATION|DATE",
+        env = "GREPOWSKI_SINCE",
+        conflicts_with = "diff",
+        value_parser = parse_since,
+        help = "Only evaluate files modified within a duration like 90m, 12h, 3d or 2w, or since a date like 2024-05-01 (UTC)"
+    )]
+    pub since: Option<SystemTime>,
+
+    #[clap(
+        long,
+        value_name = "N",
+        env = "GREPOWSKI_LIMIT_FILES",
+        conflicts_with = "diff",
+        help = "Only evaluate the first N input files, to try a question on a sample of a large input"
+    )]
+    pub limit_files: Option<usize>,
+
+    #[clap(
+        long,
+        env = "GREPOWSKI_WATCH",
+        conflicts_with = "diff",
+        help = "Evaluate the fragments of input files again when they change, until quitting"
+    )]
+    pub watch: bool,
+
+    #[clap(value_name = "FILES", required_unless_present_any = ["files_from", "diff", "fragments_jsonl", "question_file"], help = "Input files to analyze", value_hint = clap::ValueHint::FilePath
     )]
     pub files: Vec<String>,
 }
 
+impl AskArgs {
+    /// Input files given on the command line followed by the ones from the manifest, if any,
+    /// without the ones not modified since `--since`.
+    pub fn all_files(&self) -> anyhow::Result<Vec<String>> {
+        let mut files = self.files.clone();
+        if let Some(files_from) = &self.files_from {
+            files.extend(parse_manifest(&read_path_or_stdin(files_from)?));
+        }
+        if let Some(since) = self.since {
+            // files without a readable modification time are kept to show up as skipped
+            files.retain(|file| {
+                std::fs::metadata(file)
+                    .and_then(|metadata| metadata.modified())
+                    .map_or(true, |modified| modified >= since)
+            });
+        }
+        if let Some(limit_files) = self.limit_files {
+            files.truncate(limit_files);
+        }
+        Ok(files)
+    }
+
+    /// The question from `--question-file`, the positional argument or `$EDITOR`, in that order.
+    /// With `--question-file` the positional question is taken as the first input file.
+    pub fn resolve_question(&mut self) -> anyhow::Result<String> {
+        let question = match &self.question_file {
+            Some(question_file) => {
+                if let Some(file) = self.question.take() {
+                    self.files.insert(0, file);
+                }
+                read_path_or_stdin(question_file)?
+            }
+            None => match self.question.take() {
+                Some(question) => question,
+                None => question_from_editor()?,
+            },
+        };
+        if self.files.is_empty()
+            && self.files_from.is_none()
+            && self.diff.is_none()
+            && self.fragments_jsonl.is_none()
+        {
+            anyhow::bail!("No input files given");
+        }
+        let question = question.trim().to_string();
+        if question.is_empty() {
+            anyhow::bail!("Empty question");
+        }
+        self.question = Some(question.clone());
+        Ok(question)
+    }
+
+    /// Content of the diff given with `--diff`, if any.
+    pub fn diff_content(&self) -> anyhow::Result<Option<String>> {
+        self.diff.as_deref().map(read_path_or_stdin).transpose()
+    }
+
+    /// Content of the chunks given with `--fragments-jsonl`, if any.
+    pub fn fragments_jsonl_content(&self) -> anyhow::Result<Option<String>> {
+        self.fragments_jsonl
+            .as_deref()
+            .map(read_path_or_stdin)
+            .transpose()
+    }
+}
+
+const EDITOR_TEMPLATE: &str = "\n# Write the question to ask the model for each fragment. Lines starting with '#' are ignored.\n";
+
+fn question_from_editor() -> anyhow::Result<String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let mut editor = editor.split_whitespace();
+    let program = editor
+        .next()
+        .ok_or(anyhow::anyhow!("No editor configured"))?;
+    let path = std::env::temp_dir().join(format!("grepowski-question-{}.txt", std::process::id()));
+    std::fs::write(&path, EDITOR_TEMPLATE)?;
+    let status = std::process::Command::new(program)
+        .args(editor)
+        .arg(&path)
+        .status();
+    let content = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    if !status?.success() {
+        anyhow::bail!("Editor exited unsuccessfully");
+    }
+    Ok(strip_comments(&content?))
+}
+
+/// Removes the `#` comment lines of the editor template.
+fn strip_comments(content: &str) -> String {
+    content
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn read_path_or_stdin(path: &str) -> anyhow::Result<String> {
+    Ok(if path == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(path)?
+    })
+}
+
+fn parse_positive(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(value) if value > 0.0 => Ok(value),
+        Ok(_) => Err("must be greater than 0".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// A point in time from a duration before now (`90m`, `12h`, `3d`, `2w`, `30s`) or a date
+/// (`2024-05-01`, midnight UTC).
+fn parse_since(s: &str) -> Result<SystemTime, String> {
+    if let Some(date) = parse_date(s) {
+        let (year, month, day) = date?;
+        let days = days_from_civil(year, month, day);
+        let secs = u64::try_from(days * 86400).map_err(|_| format!("date before 1970: {}", s))?;
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or(format!("missing unit in '{}', use s, m, h, d or w", s))?;
+    let (value, unit) = s.split_at(unit_start);
+    let value: u64 = value.parse().map_err(|_| {
+        format!(
+            "expected a duration like 3d or a date like 2024-05-01, got '{}'",
+            s
+        )
+    })?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => return Err(format!("unknown unit '{}', use s, m, h, d or w", unit)),
+    };
+    SystemTime::now()
+        .checked_sub(Duration::from_secs(value.saturating_mul(unit_secs)))
+        .ok_or(format!("duration too long: {}", s))
+}
+
+/// `None` unless `s` looks like `YYYY-MM-DD`, an error if it does but is no valid date.
+fn parse_date(s: &str) -> Option<Result<(i64, u32, u32), String>> {
+    let mut parts = s.splitn(3, '-');
+    let year = parts.next()?;
+    let month = parts.next()?;
+    let day = parts.next()?;
+    let digits = |part: &str, len| part.len() == len && part.bytes().all(|b| b.is_ascii_digit());
+    if !digits(year, 4) || !digits(month, 2) || !digits(day, 2) {
+        return None;
+    }
+    let (year, month, day) = (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+    let valid = (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day);
+    Some(if valid {
+        Ok((year, month, day))
+    } else {
+        Err(format!("invalid date: {}", s))
+    })
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or(format!("expected KEY=VALUE, got '{}'", s))?;
+    Ok((key.trim_start_matches('.').to_string(), value.to_string()))
+}
+
+/// Paths from a manifest, ignoring blank lines and `#` comments.
+fn parse_manifest(manifest: &str) -> Vec<String> {
+    manifest
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Value of `--env-file` in `args`, which has to be known before parsing as it provides
+/// defaults for other options.
+fn env_file_arg(args: impl IntoIterator<Item = OsString>) -> Option<PathBuf> {
+    const PREFIX: &str = "--env-file=";
+    let mut args = args.into_iter().take_while(|arg| arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == "--env-file" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.as_encoded_bytes().strip_prefix(PREFIX.as_bytes()) {
+            // SAFETY: split right after the valid UTF-8 prefix of an OsStr
+            let path = unsafe { OsStr::from_encoded_bytes_unchecked(path) };
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
 pub fn parse() -> Cli {
+    let env_file = env_file_arg(std::env::args_os())
+        .or_else(|| std::env::var_os("GREPOWSKI_ENV_FILE").map(PathBuf::from));
+    if let Some(env_file) = env_file
+        && let Err(e) = dotenvy::from_path(&env_file)
+    {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::Io,
+                format!("error loading {}: {}", env_file.display(), e),
+            )
+            .exit();
+    }
     Cli::parse()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AuthScheme, Cli, Command, EDITOR_TEMPLATE, EndpointArgs, ProviderPreset, env_file_arg,
+        parse_manifest, parse_since, strip_comments,
+    };
+    use std::{
+        ffi::{OsStr, OsString},
+        path::PathBuf,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn since_parses_durations_and_dates() -> Result<(), String> {
+        assert_eq!(
+            parse_since("2024-05-01")?,
+            UNIX_EPOCH + Duration::from_secs(1_714_521_600)
+        );
+        assert_eq!(parse_since("1970-01-01")?, UNIX_EPOCH);
+        let two_days_ago = parse_since("2d")?;
+        let expected = SystemTime::now() - Duration::from_secs(2 * 86400);
+        assert!(expected.duration_since(two_days_ago).unwrap_or_default() < Duration::from_secs(5));
+        assert!(parse_since("3").is_err());
+        assert!(parse_since("3y").is_err());
+        assert_eq!(
+            parse_since("2024-13-01"),
+            Err("invalid date: 2024-13-01".to_string())
+        );
+        assert_eq!(
+            parse_since("2023-02-29"),
+            Err("invalid date: 2023-02-29".to_string())
+        );
+        assert!(parse_since("2024-02-29").is_ok());
+        assert!(parse_since("2024-02-31").is_err());
+        assert!(parse_since("2024-04-31").is_err());
+        assert!(parse_since("yesterday").is_err());
+        Ok(())
+    }
+    use clap::{CommandFactory, Parser};
+
+    #[test]
+    fn cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn env_file_is_found_before_parsing() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            env_file_arg(args(&[
+                "grepowski",
+                "ask",
+                "--env-file",
+                "ci.env",
+                "-m",
+                "x"
+            ])),
+            Some(PathBuf::from("ci.env"))
+        );
+        assert_eq!(
+            env_file_arg(args(&["grepowski", "--env-file=.env", "models"])),
+            Some(PathBuf::from(".env"))
+        );
+        assert_eq!(
+            env_file_arg(args(&["grepowski", "ask", "--", "--env-file", "x"])),
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_file_is_found_next_to_non_utf8_arguments() {
+        use std::os::unix::ffi::OsStrExt;
+        let non_utf8 = OsStr::from_bytes(b"caf\xe9.rs");
+        assert_eq!(
+            env_file_arg([
+                OsString::from("grepowski"),
+                non_utf8.to_os_string(),
+                OsString::from("--env-file=ci.env")
+            ]),
+            Some(PathBuf::from("ci.env"))
+        );
+        let mut arg = OsString::from("--env-file=");
+        arg.push(non_utf8);
+        assert_eq!(
+            env_file_arg([OsString::from("grepowski"), arg]),
+            Some(PathBuf::from(non_utf8))
+        );
+    }
+
+    #[test]
+    fn block_sizes_must_be_positive() {
+        let parse = |flag: &str, value: &str| {
+            Cli::try_parse_from([
+                "grepowski",
+                "ask",
+                "-m",
+                "model",
+                flag,
+                value,
+                "question",
+                "file",
+            ])
+        };
+        assert!(parse("--lines-per-block", "0").is_err());
+        assert!(parse("--blocks-per-fragment", "0").is_err());
+        assert!(parse("--lines-per-block", "1").is_ok());
+    }
+
+    #[test]
+    fn limit_files_keeps_the_first_files() -> anyhow::Result<()> {
+        let cli = Cli::try_parse_from([
+            "grepowski",
+            "ask",
+            "-m",
+            "model",
+            "--limit-files",
+            "2",
+            "question",
+            "a.rs",
+            "b.rs",
+            "c.rs",
+        ])?;
+        let Command::Ask(args) = cli.command else {
+            anyhow::bail!("ask expected");
+        };
+        assert_eq!(args.all_files()?, ["a.rs", "b.rs"]);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_manifest_skips_blank_lines_and_comments() {
+        let manifest = "src/main.rs\n\n# generated\n  src/lib.rs  \n";
+        assert_eq!(parse_manifest(manifest), vec!["src/main.rs", "src/lib.rs"]);
+    }
+
+    #[test]
+    fn url_overrides_provider_preset() {
+        let mut endpoint = EndpointArgs {
+            provider_preset: None,
+            url: Vec::new(),
+            auth_token: None,
+            auth_scheme: AuthScheme::Bearer,
+            proxy: None,
+        };
+        assert_eq!(endpoint.url(), "http://127.0.0.1:8080/v1");
+        endpoint.provider_preset = Some(ProviderPreset::Groq);
+        assert_eq!(endpoint.url(), "https://api.groq.com/openai/v1");
+        endpoint.url = vec![
+            "http://localhost:1234/v1".to_string(),
+            "http://localhost:1235/v1".to_string(),
+        ];
+        assert_eq!(endpoint.url(), "http://localhost:1234/v1");
+        assert_eq!(endpoint.fallback_urls(), ["http://localhost:1235/v1"]);
+    }
+
+    #[test]
+    fn strip_comments_removes_editor_template() {
+        let content = format!("Does this code\nallocate?{}", EDITOR_TEMPLATE);
+        assert_eq!(strip_comments(&content).trim(), "Does this code\nallocate?");
+    }
+}
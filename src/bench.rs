@@ -0,0 +1,99 @@
+use crate::args::BenchArgs;
+use futures_util::StreamExt;
+use grepowski::ai_query::{AI, DefaultAiQueryConfig};
+use std::time::{Duration, Instant};
+
+pub(crate) const BENCH_QUESTION: &str = "Does this code iterate over a collection?";
+pub(crate) const BENCH_FRAGMENT: &str = r#"fn mean(values: &[f32]) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    let sum: f32 = values.iter().sum();
+    Some(sum / values.len() as f32)
+}"#;
+
+/// Nearest rank percentile of ascending `sorted` latencies.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// Sends the same fragment `args.requests` times, `args.concurrency` at a time, and prints
+/// throughput and latencies.
+pub async fn run(args: BenchArgs) -> anyhow::Result<()> {
+    let ai = AI::new(
+        args.model,
+        args.endpoint.url(),
+        args.endpoint.auth_token(),
+        args.temperature,
+        DefaultAiQueryConfig,
+        BENCH_QUESTION,
+    )
+    .with_auth_scheme(args.endpoint.auth_scheme.into())
+    .with_fallback_urls(args.endpoint.fallback_urls())
+    .with_proxy(args.endpoint.proxy.as_deref())?
+    .with_rate_limit(args.rate_limit);
+
+    let start = Instant::now();
+    let results: Vec<_> = futures::stream::iter(0..args.requests)
+        .map(|_| async {
+            let request_start = Instant::now();
+            ai.query(BENCH_FRAGMENT)
+                .await
+                .map(|_| request_start.elapsed())
+        })
+        .buffer_unordered(args.concurrency)
+        .collect()
+        .await;
+    let total = start.elapsed();
+
+    let mut latencies = Vec::new();
+    let mut failures = 0;
+    for result in results {
+        match result {
+            Ok(latency) => latencies.push(latency),
+            Err(e) => {
+                if failures == 0 {
+                    eprintln!("First failure: {}", e);
+                }
+                failures += 1;
+            }
+        }
+    }
+    latencies.sort();
+
+    println!(
+        "Requests: {} ({} failed), concurrency {}",
+        args.requests, failures, args.concurrency
+    );
+    println!(
+        "Total: {:.2}s, {:.2} requests/s",
+        total.as_secs_f64(),
+        latencies.len() as f64 / total.as_secs_f64()
+    );
+    println!(
+        "Latency: p50 {:.2}s, p90 {:.2}s, p99 {:.2}s, max {:.2}s",
+        percentile(&latencies, 50).as_secs_f64(),
+        percentile(&latencies, 90).as_secs_f64(),
+        percentile(&latencies, 99).as_secs_f64(),
+        latencies.last().copied().unwrap_or_default().as_secs_f64()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_uses_nearest_rank() {
+        let latencies: Vec<_> = (1..=10).map(Duration::from_secs).collect();
+        assert_eq!(percentile(&latencies, 50), Duration::from_secs(5));
+        assert_eq!(percentile(&latencies, 90), Duration::from_secs(9));
+        assert_eq!(percentile(&latencies, 99), Duration::from_secs(10));
+        assert_eq!(percentile(&[], 50), Duration::ZERO);
+    }
+}
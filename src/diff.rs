@@ -0,0 +1,127 @@
+//! Changed lines of unified diffs, e.g. from `git diff`.
+
+use std::path::PathBuf;
+
+/// Line ranges added per file by a unified diff, 0-based and inclusive in the new version of
+/// the file. Deleted files and files without added lines are left out, renamed files are listed
+/// under their new path.
+pub fn changed_ranges(diff: &str) -> Vec<(PathBuf, Vec<(usize, usize)>)> {
+    let mut files: Vec<(PathBuf, Vec<(usize, usize)>)> = Vec::new();
+    let mut current = false;
+    let mut new_line = 0;
+    let mut old_remaining: usize = 0;
+    let mut new_remaining: usize = 0;
+
+    for line in diff.lines() {
+        if old_remaining > 0 || new_remaining > 0 {
+            match line.chars().next() {
+                Some('+') => {
+                    if current && let Some((_, ranges)) = files.last_mut() {
+                        match ranges.last_mut() {
+                            Some((_, last)) if *last + 1 == new_line => *last = new_line,
+                            _ => ranges.push((new_line, new_line)),
+                        }
+                    }
+                    new_line += 1;
+                    new_remaining = new_remaining.saturating_sub(1);
+                }
+                // hand-edited hunks may have more lines than their header counts, the hunk ends
+                // once both counts are used up
+                Some('-') => old_remaining = old_remaining.saturating_sub(1),
+                Some('\\') => {}
+                _ => {
+                    new_line += 1;
+                    old_remaining = old_remaining.saturating_sub(1);
+                    new_remaining = new_remaining.saturating_sub(1);
+                }
+            }
+        } else if let Some(path) = line.strip_prefix("+++ ") {
+            let path = path.split('\t').next().unwrap_or(path).trim_end();
+            current = path != "/dev/null";
+            if current {
+                let path = path.strip_prefix("b/").unwrap_or(path);
+                files.push((PathBuf::from(path), Vec::new()));
+            }
+        } else if let Some((old, new)) = parse_hunk_header(line) {
+            (old_remaining, new_remaining) = (old.1, new.1);
+            new_line = new.0.saturating_sub(1);
+        }
+    }
+
+    files.retain(|(_, ranges)| !ranges.is_empty());
+    files
+}
+
+/// Start and line count of the old and new side of a `@@ -a,b +c,d @@` header.
+fn parse_hunk_header(line: &str) -> Option<((usize, usize), (usize, usize))> {
+    let mut parts = line.strip_prefix("@@ ")?.split_whitespace();
+    let old = parse_hunk_range(parts.next()?.strip_prefix('-')?)?;
+    let new = parse_hunk_range(parts.next()?.strip_prefix('+')?)?;
+    Some((old, new))
+}
+
+fn parse_hunk_range(range: &str) -> Option<(usize, usize)> {
+    match range.split_once(',') {
+        Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+        None => Some((range.parse().ok()?, 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_ranges_follow_new_line_numbers() {
+        let diff = "\
+diff --git a/src/a.rs b/src/a.rs
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -1,4 +1,4 @@
+ fn one() {}
+-fn two() {}
++fn two() { 2 }
++fn two_and_a_half() {}
+ fn three() {}
+--- comment removed
+@@ -10,2 +11,3 @@ fn ten() {}
+ fn ten() {}
++fn eleven() {}
+ fn twelve() {}
+diff --git a/src/old.rs b/src/old.rs
+deleted file mode 100644
+--- a/src/old.rs
++++ /dev/null
+@@ -1 +0,0 @@
+-fn gone() {}
+";
+        assert_eq!(
+            changed_ranges(diff),
+            vec![(PathBuf::from("src/a.rs"), vec![(1, 2), (11, 11)])]
+        );
+    }
+
+    #[test]
+    fn miscounted_hunk_ends_before_the_next_file() {
+        let diff = "\
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -1,1 +1,2 @@
+-fn one() {}
+-fn two() {}
+ fn three() {}
++fn four() {}
+--- a/src/b.rs
++++ b/src/b.rs
+@@ -1,0 +1,1 @@
++fn five() {}
+";
+        assert_eq!(
+            changed_ranges(diff),
+            vec![
+                (PathBuf::from("src/a.rs"), vec![(1, 1)]),
+                (PathBuf::from("src/b.rs"), vec![(0, 0)])
+            ]
+        );
+    }
+}
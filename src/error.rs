@@ -0,0 +1,39 @@
+/// Errors of reading files and querying the model, to tell their kinds apart.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Reading an input file or writing the request log failed.
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    /// Loading syntax definitions or highlighting a file failed.
+    #[error("{0}")]
+    Syntax(String),
+    /// The request couldn't be sent or the response not received.
+    #[error("{0}")]
+    Network(#[from] reqwest::Error),
+    /// An endpoint URL couldn't be parsed.
+    #[error("Invalid URL {0}")]
+    Url(String),
+    /// The endpoint rejected the auth token.
+    #[error("Authentication failed with status {status}: {body}")]
+    Auth { status: u16, body: String },
+    /// The endpoint answered with an error status, e.g. while overloaded.
+    #[error("Request failed with status {status}: {body}")]
+    Status { status: u16, body: String },
+    /// The response doesn't have the expected format, e.g. no score could be extracted.
+    #[error("{0}")]
+    Parse(String),
+    /// The file has more lines than [`crate::fragment::FragmentOptions::max_lines`].
+    #[error("more than {0} lines")]
+    TooManyLines(usize),
+    /// The model declined to answer.
+    #[error("Model refused: {0}")]
+    Refusal(String),
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Parse(value.to_string())
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,241 @@
+//! Self-contained HTML page of the results and SVG chart of their values, to share them outside
+//! of the terminal.
+
+use crate::{fragment_evaluation::FragmentEvaluation, theme::Theme};
+use ratatui::{
+    style::{Color, Modifier, Style},
+    text::Line,
+};
+use std::fmt::Write;
+
+/// Page listing `eval` in the given order with their values, reasons and highlighted code, headed
+/// by the question and the model. Colors are taken from `theme`.
+pub fn html(question: &str, model: &str, eval: &[FragmentEvaluation], theme: Theme) -> String {
+    let mut page = String::new();
+    let _ = write!(
+        page,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>grepowski: {question}</title>\n<style>\n\
+         body {{ background: {background}; color: {text}; font-family: sans-serif; margin: 2em; }}\n\
+         h1, h2 {{ color: {title}; }}\n\
+         h2 {{ font-size: 1em; font-family: monospace; }}\n\
+         .value {{ color: {highlight}; font-weight: bold; }}\n\
+         pre {{ border: 1px solid {border}; border-radius: 4px; padding: 0.5em; overflow-x: auto; }}\n\
+         </style>\n</head>\n<body>\n<h1>{question}</h1>\n<p>Model: {model}, {count} fragment(s)</p>\n",
+        question = escape(question),
+        model = escape(model),
+        count = eval.len(),
+        background = css_color(theme.background).unwrap_or_default(),
+        text = css_color(theme.text).unwrap_or_default(),
+        title = css_color(theme.title).unwrap_or_default(),
+        highlight = css_color(theme.highlight).unwrap_or_default(),
+        border = css_color(theme.border).unwrap_or_default(),
+    );
+    for eval in eval {
+        let _ = write!(
+            page,
+            "<section>\n<h2>{} <span class=\"value\">{}</span></h2>\n",
+            escape(&eval.fragment.location()),
+            escape(&eval.value_label())
+        );
+        if let Some(reason) = &eval.reason {
+            let _ = writeln!(page, "<p>{}</p>", escape(reason));
+        }
+        page.push_str("<pre>");
+        for line in eval.fragment.highlighted_content() {
+            page.push_str(&html_line(&line));
+            page.push('\n');
+        }
+        page.push_str("</pre>\n</section>\n");
+    }
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+/// Value history chart of `values` in the order they were gathered, like the one shown while
+/// gathering, with the mean as a horizontal line. Colors are taken from `theme`.
+pub fn svg_chart(values: &[f32], theme: Theme) -> String {
+    let (width, height, margin) = (800.0, 300.0, 40.0);
+    let (plot_width, plot_height) = (width - 2.0 * margin, height - 2.0 * margin);
+    let x = |idx: usize| {
+        margin
+            + match values.len() {
+                0 | 1 => plot_width / 2.0,
+                len => idx as f64 / (len - 1) as f64 * plot_width,
+            }
+    };
+    let y = |value: f32| margin + (1.0 - value.clamp(0.0, 1.0) as f64) * plot_height;
+    let color = |color: Color| css_color(color).unwrap_or_else(|| "currentColor".to_string());
+
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\" font-family=\"sans-serif\" font-size=\"12\">\n\
+         <rect width=\"100%\" height=\"100%\" fill=\"{background}\"/>\n\
+         <rect x=\"{margin}\" y=\"{margin}\" width=\"{plot_width}\" height=\"{plot_height}\" fill=\"none\" stroke=\"{border}\"/>\n\
+         <text x=\"{margin}\" y=\"{title_y}\" fill=\"{title}\" font-weight=\"bold\">Value history ({count} values)</text>\n",
+        background = color(theme.background),
+        border = color(theme.border),
+        title = color(theme.title),
+        title_y = margin - 12.0,
+        count = values.len(),
+    );
+    for value in [0.0, 0.5, 1.0] {
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" fill=\"{}\" text-anchor=\"end\" dominant-baseline=\"middle\">{:.1}</text>",
+            margin - 6.0,
+            y(value),
+            color(theme.text),
+            value
+        );
+    }
+    if let Some(mean) =
+        (!values.is_empty()).then(|| values.iter().sum::<f32>() / values.len() as f32)
+    {
+        let _ = writeln!(
+            svg,
+            "<line x1=\"{margin}\" y1=\"{mean_y}\" x2=\"{x2}\" y2=\"{mean_y}\" stroke=\"{title}\" stroke-dasharray=\"4 4\"/>\n\
+             <text x=\"{x2}\" y=\"{title_y}\" fill=\"{text}\" text-anchor=\"end\">mean {mean:.3}</text>",
+            mean_y = y(mean),
+            x2 = margin + plot_width,
+            title = color(theme.title),
+            title_y = margin - 12.0,
+            text = color(theme.text),
+        );
+    }
+    let points = values
+        .iter()
+        .enumerate()
+        .map(|(idx, &value)| format!("{:.1},{:.1}", x(idx), y(value)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let _ = writeln!(
+        svg,
+        "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\"/>",
+        points,
+        color(theme.text)
+    );
+    if let Some(&latest) = values.last() {
+        let _ = writeln!(
+            svg,
+            "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\" fill=\"{}\"/>",
+            x(values.len() - 1),
+            y(latest),
+            color(theme.highlight)
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// `line` as HTML with a styled span for each of its spans.
+fn html_line(line: &Line) -> String {
+    line.spans
+        .iter()
+        .map(|span| {
+            let css = css_style(line.style.patch(span.style));
+            let content = escape(&span.content);
+            if css.is_empty() {
+                content
+            } else {
+                format!("<span style=\"{}\">{}</span>", css, content)
+            }
+        })
+        .collect()
+}
+
+fn css_style(style: Style) -> String {
+    let mut css = String::new();
+    if let Some(color) = style.fg.and_then(css_color) {
+        let _ = write!(css, "color: {};", color);
+    }
+    if style.add_modifier.contains(Modifier::BOLD) {
+        css.push_str("font-weight: bold;");
+    }
+    if style.add_modifier.contains(Modifier::ITALIC) {
+        css.push_str("font-style: italic;");
+    }
+    if style.add_modifier.contains(Modifier::UNDERLINED) {
+        css.push_str("text-decoration: underline;");
+    }
+    // context lines around the fragment
+    if style.add_modifier.contains(Modifier::DIM) {
+        css.push_str("opacity: 0.5;");
+    }
+    css
+}
+
+/// Only RGB colors, named terminal colors depend on the terminal.
+fn css_color(color: Color) -> Option<String> {
+    match color {
+        Color::Rgb(r, g, b) => Some(format!("#{:02x}{:02x}{:02x}", r, g, b)),
+        _ => None,
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::{FragmentSize, file_to_fragments};
+    use tempfile::tempdir;
+
+    #[test]
+    fn html_escapes_and_lists_fragments() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("sample.rs");
+        std::fs::write(&file_path, "fn one() -> Vec<u8> { vec![] }\n")?;
+        let fragment =
+            file_to_fragments(&file_path, FragmentSize::WholeFile, Theme::synthwave())?.remove(0);
+        let eval = FragmentEvaluation {
+            fragment,
+            value: 0.75,
+            reason: Some("returns a <Vec>".to_string()),
+            std_dev: None,
+            raw: None,
+            raw_value: None,
+            change: None,
+        };
+
+        let page = html(
+            "Does it \"allocate\"?",
+            "my-model",
+            &[eval],
+            Theme::synthwave(),
+        );
+
+        assert!(page.contains("<h1>Does it &quot;allocate&quot;?</h1>"));
+        assert!(page.contains("Model: my-model, 1 fragment(s)"));
+        assert!(page.contains("<span class=\"value\">0.750</span>"));
+        assert!(page.contains("<p>returns a &lt;Vec&gt;</p>"));
+        assert!(page.contains("&lt;") && !page.contains("<u8"));
+        assert!(page.contains("<span style=\"color: #"));
+        Ok(())
+    }
+
+    #[test]
+    fn svg_chart_plots_values_and_mean() {
+        let svg = svg_chart(&[0.0, 1.0, 0.5], Theme::synthwave());
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.contains("Value history (3 values)"));
+        assert!(svg.contains("<polyline points=\"40.0,260.0 400.0,40.0 760.0,150.0\""));
+        assert!(svg.contains("mean 0.500"));
+        assert!(svg.contains("<circle cx=\"760.0\" cy=\"150.0\""));
+
+        let empty = svg_chart(&[], Theme::synthwave());
+        assert!(!empty.contains("mean") && !empty.contains("<circle"));
+    }
+}
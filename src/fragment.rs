@@ -1,135 +1,771 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
 
-use crate::tui::{SyntectTheme, Theme};
-use ratatui::text::{Line, Span};
+use crate::{
+    Error,
+    theme::{SyntectTheme, Theme},
+};
+use ratatui::{
+    style::Modifier,
+    text::{Line, Span},
+};
+use serde::Deserialize;
 use std::sync::Arc;
-use syntect::{easy::HighlightLines, parsing::SyntaxSet, util::LinesWithEndings};
+use syntect::{
+    highlighting::{HighlightIterator, HighlightState, Highlighter},
+    parsing::{ParseState, Scope, ScopeStack, ScopeStackOp, SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
 use syntect_tui::into_span;
 
+const WHOLE_FILE: (usize, usize) = (0, usize::MAX);
+
+/// Prompt of a fragment with its path and line range, see [`Fragment::with_path_in_prompt`].
+const PATH_PROMPT_TEMPLATE: &str = "File: {path} (lines {lines})\n{code}";
+
+/// Rough average of bytes per token, used to approximate token based fragment sizes.
+const BYTES_PER_TOKEN: usize = 4;
+
+/// Scopes of the names of functions, classes and similar definitions in the default syntaxes.
+const DEFINITION_SCOPES: &[&str] = &[
+    "entity.name.function",
+    "entity.name.class",
+    "entity.name.struct",
+    "entity.name.enum",
+    "entity.name.trait",
+    "entity.name.impl",
+    "entity.name.interface",
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum FragmentSize {
+    /// Overlapping fragments of `blocks_per_fragment` blocks, starting every `lines_per_block` lines.
+    Lines {
+        lines_per_block: usize,
+        blocks_per_fragment: usize,
+    },
+    /// Consecutive fragments of whole lines, each at most the given number of bytes unless a
+    /// single line exceeds it.
+    Bytes(usize),
+    /// A single fragment spanning the whole file, or the whole line range.
+    WholeFile,
+}
+
+impl FragmentSize {
+    pub fn tokens(tokens: usize) -> Self {
+        Self::Bytes(tokens * BYTES_PER_TOKEN)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FragmentOptions {
+    pub size: FragmentSize,
+    /// Fragments with fewer lines are dropped.
+    pub min_lines: usize,
+    /// Pairs of file name suffix (e.g. `rs.in`) and syntax name or extension to use for matching
+    /// files instead of the one detected from the extension.
+    pub syntax_overrides: Vec<(String, String)>,
+    /// Directory with additional `.sublime-syntax` definitions, see [`syntax_set`].
+    pub syntax_dir: Option<PathBuf>,
+    /// Start a fragment at each top-level definition, see [`DefinitionFinder`]. Ranges without
+    /// a detected definition, or larger than `size`, are split by `size` instead.
+    pub by_symbol: bool,
+    /// Files with more lines are skipped before highlighting, e.g. generated code.
+    pub max_lines: Option<usize>,
+    /// Skip syntax highlighting, the main cost of reading large inputs, when the code is never
+    /// displayed.
+    pub no_highlight: bool,
+}
+
+impl From<FragmentSize> for FragmentOptions {
+    fn from(size: FragmentSize) -> Self {
+        Self {
+            size,
+            min_lines: 0,
+            syntax_overrides: Vec::new(),
+            syntax_dir: None,
+            by_symbol: false,
+            max_lines: None,
+            no_highlight: false,
+        }
+    }
+}
+
+/// The default syntaxes plus the ones found in `syntax_dir`. Each set is only built once.
+pub fn syntax_set(syntax_dir: Option<&Path>) -> crate::Result<Arc<SyntaxSet>> {
+    static SYNTAX_SETS: OnceLock<Mutex<HashMap<Option<PathBuf>, Arc<SyntaxSet>>>> = OnceLock::new();
+    let mut syntax_sets = SYNTAX_SETS
+        .get_or_init(Default::default)
+        .lock()
+        .map_err(|_| Error::Syntax("Syntax set cache poisoned".to_string()))?;
+    let key = syntax_dir.map(Path::to_path_buf);
+    if let Some(syntax_set) = syntax_sets.get(&key) {
+        return Ok(syntax_set.clone());
+    }
+    let syntax_set = match syntax_dir {
+        Some(syntax_dir) => {
+            let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+            builder.add_from_folder(syntax_dir, true).map_err(|e| {
+                Error::Syntax(format!(
+                    "error loading syntaxes from {}: {}",
+                    syntax_dir.display(),
+                    e
+                ))
+            })?;
+            builder.build()
+        }
+        None => SyntaxSet::load_defaults_newlines(),
+    };
+    let syntax_set = Arc::new(syntax_set);
+    syntax_sets.insert(key, syntax_set.clone());
+    Ok(syntax_set)
+}
+
+/// Syntax for `path`, preferring the longest matching override and falling back to plain text.
+fn find_syntax<'a>(
+    ps: &'a SyntaxSet,
+    path: &Path,
+    syntax_overrides: &[(String, String)],
+) -> &'a SyntaxReference {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let overridden = syntax_overrides
+        .iter()
+        .filter(|(suffix, _)| file_name.ends_with(&format!(".{}", suffix)))
+        .max_by_key(|(suffix, _)| suffix.len())
+        .and_then(|(_, syntax)| {
+            ps.find_syntax_by_name(syntax)
+                .or_else(|| ps.find_syntax_by_extension(syntax))
+        });
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+    overridden
+        .or_else(|| ps.find_syntax_by_extension(&ext))
+        .unwrap_or_else(|| ps.find_syntax_plain_text())
+}
+
+/// Finds the lines defining a function, class or similar at the top level, i.e. outside of any
+/// other scope than the one of the file itself. Finds none if the syntax doesn't mark definitions.
+struct DefinitionFinder {
+    scopes: Vec<Scope>,
+    stack: ScopeStack,
+}
+
+impl DefinitionFinder {
+    fn new() -> crate::Result<Self> {
+        let scopes = DEFINITION_SCOPES
+            .iter()
+            .map(|scope| Scope::new(scope).map_err(|e| Error::Syntax(format!("{:?}", e))))
+            .collect::<crate::Result<Vec<_>>>()?;
+        Ok(Self {
+            scopes,
+            stack: ScopeStack::new(),
+        })
+    }
+
+    /// Whether the next line, parsed into `ops`, defines something at the top level.
+    fn defines(&mut self, ops: &[(usize, ScopeStackOp)]) -> crate::Result<bool> {
+        let top_level = self.stack.len() <= 1;
+        let mut defines = false;
+        for (_, op) in ops {
+            if let ScopeStackOp::Push(scope) = op {
+                defines |= self
+                    .scopes
+                    .iter()
+                    .any(|definition| definition.is_prefix_of(*scope));
+            }
+            self.stack
+                .apply(op)
+                .map_err(|e| Error::Syntax(format!("{:?}", e)))?;
+        }
+        Ok(top_level && defines)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct FileLine {
     line: String,
-    highlighted_line: Line<'static>,
+    /// Not built when highlighting is skipped, see [`FragmentOptions::no_highlight`].
+    highlighted_line: Option<Line<'static>>,
 }
 
 #[derive(Debug, Clone)]
 struct File {
     path: PathBuf,
+    language: String,
+    /// Zero based index of the first line of `content` in the file, only set for chunks read
+    /// from elsewhere, see [`chunk_to_fragment`].
+    first_line: usize,
     content: Vec<FileLine>,
+    /// Lines starting a definition, only detected when splitting by symbol.
+    definitions: Vec<usize>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Fragment {
     first_line: usize,
     last_line: usize,
+    context_lines: usize,
+    path_in_prompt: bool,
+    prompt_template: Option<Arc<str>>,
     file: Arc<File>,
 }
 
 impl File {
-    fn read<P: AsRef<Path>>(file: P, theme: SyntectTheme) -> anyhow::Result<Self> {
+    fn read<P: AsRef<Path>>(
+        file: P,
+        options: &FragmentOptions,
+        theme: SyntectTheme,
+    ) -> crate::Result<Self> {
         let path = file.as_ref().to_path_buf();
         let content = std::fs::read_to_string(file)?;
+        if let Some(max_lines) = options.max_lines
+            && content.lines().count() > max_lines
+        {
+            return Err(Error::TooManyLines(max_lines));
+        }
+        Self::from_content(path, &content, options, theme)
+    }
 
-        let ext = path.extension().unwrap_or_default();
-
-        let ps = SyntaxSet::load_defaults_newlines();
-
-        let syntax = ps.find_syntax_by_extension(ext.to_str().unwrap()).unwrap();
-
-        let mut highlight = HighlightLines::new(syntax, &theme);
+    /// Highlights `content` with the syntax detected from `path`, unless
+    /// [`FragmentOptions::no_highlight`] is set. Each line is parsed once, for both highlighting
+    /// and finding definitions.
+    fn from_content(
+        path: PathBuf,
+        content: &str,
+        options: &FragmentOptions,
+        theme: SyntectTheme,
+    ) -> crate::Result<Self> {
+        let ps = syntax_set(options.syntax_dir.as_deref())?;
 
-        let lines = content.lines();
+        let syntax = find_syntax(&ps, &path, &options.syntax_overrides);
 
-        let highlighted_lines =
-            LinesWithEndings::from(&content).flat_map(|line| -> anyhow::Result<Line> {
-                Ok(Line::from_iter(
-                    highlight
-                        .highlight_line(line, &ps)?
-                        .into_iter()
-                        .filter_map(|segment| {
-                            into_span(segment)
-                                .ok()
-                                .map(|span| Span::styled(span.content.into_owned(), span.style))
-                        }),
-                ))
-            });
+        let highlighter = Highlighter::new(&theme);
+        let mut highlight_state = HighlightState::new(&highlighter, ScopeStack::new());
+        let mut parse_state = ParseState::new(syntax);
+        let mut finder = options.by_symbol.then(DefinitionFinder::new).transpose()?;
 
-        let merged: Vec<_> = lines
-            .zip(highlighted_lines)
-            .map(|(line, highlighted_line)| FileLine {
+        let mut merged = Vec::new();
+        let mut definitions = Vec::new();
+        for (idx, (line, line_with_ending)) in content
+            .lines()
+            .zip(LinesWithEndings::from(content))
+            .enumerate()
+        {
+            let mut highlighted_line = None;
+            if !options.no_highlight || finder.is_some() {
+                let ops = parse_state
+                    .parse_line(line_with_ending, &ps)
+                    .map_err(|e| Error::Syntax(e.to_string()))?;
+                if let Some(finder) = &mut finder
+                    && finder.defines(&ops)?
+                {
+                    definitions.push(idx);
+                }
+                if !options.no_highlight {
+                    let segments = HighlightIterator::new(
+                        &mut highlight_state,
+                        &ops,
+                        line_with_ending,
+                        &highlighter,
+                    );
+                    highlighted_line = Some(Line::from_iter(segments.filter_map(|segment| {
+                        into_span(segment)
+                            .ok()
+                            .map(|span| Span::styled(span.content.into_owned(), span.style))
+                    })));
+                }
+            }
+            merged.push(FileLine {
                 line: line.into(),
                 highlighted_line,
-            })
-            .collect();
+            });
+        }
 
         let result = Self {
             path,
+            language: syntax.name.clone(),
+            first_line: 0,
             content: merged,
+            definitions,
         };
 
         Ok(result)
     }
 
+    /// Splits each of the line `ranges` (0-based, inclusive, clamped to the file) separately.
     pub fn into_fragments(
         self,
-        lines_per_block: usize,
-        blocks_per_fragment: usize,
+        options: &FragmentOptions,
+        ranges: &[(usize, usize)],
     ) -> Vec<Fragment> {
         let file = Arc::new(self);
+        ranges
+            .iter()
+            .flat_map(|&range| Self::split(&file, options, range))
+            .collect()
+    }
 
+    fn split(
+        file: &Arc<File>,
+        options: &FragmentOptions,
+        (first, last): (usize, usize),
+    ) -> Vec<Fragment> {
         let num_lines = file.content.len();
-        let start_lines = (0..num_lines).step_by(lines_per_block);
-
-        start_lines
-            .map(|first_line| {
-                let last_line = std::cmp::min(
-                    first_line + lines_per_block * blocks_per_fragment,
-                    num_lines - 1,
-                );
-                Fragment {
-                    file: file.clone(),
-                    first_line,
-                    last_line,
+        let (start, end) = (first.min(num_lines), last.saturating_add(1).min(num_lines));
+
+        let definitions: Vec<usize> = file
+            .definitions
+            .iter()
+            .copied()
+            .filter(|line| (start..end).contains(line))
+            .collect();
+        let fragments = if definitions.is_empty() {
+            Self::split_by_size(file, options.size, start, end)
+        } else {
+            Self::split_at(file, start, end, &definitions)
+                .into_iter()
+                .flat_map(|symbol| {
+                    let parts = Self::split_by_size(
+                        file,
+                        options.size,
+                        symbol.first_line,
+                        symbol.last_line + 1,
+                    );
+                    // keeps a symbol whole unless it is larger than a single fragment of `size`
+                    if parts
+                        .first()
+                        .is_some_and(|part| part.last_line < symbol.last_line)
+                    {
+                        parts
+                    } else {
+                        vec![symbol]
+                    }
+                })
+                .collect()
+        };
+
+        Self::apply_min_lines(fragments, options.min_lines)
+    }
+
+    /// Fragments from `start` to `end` (exclusive) of the given `size`.
+    fn split_by_size(
+        file: &Arc<File>,
+        size: FragmentSize,
+        start: usize,
+        end: usize,
+    ) -> Vec<Fragment> {
+        match size {
+            FragmentSize::Lines {
+                lines_per_block,
+                blocks_per_fragment,
+            } => {
+                let start_lines = (start..end).step_by(lines_per_block);
+
+                start_lines
+                    .map(|first_line| {
+                        let last_line = std::cmp::min(
+                            first_line + lines_per_block * blocks_per_fragment,
+                            end - 1,
+                        );
+                        Fragment {
+                            file: file.clone(),
+                            first_line,
+                            last_line,
+                            context_lines: 0,
+                            path_in_prompt: false,
+                            prompt_template: None,
+                        }
+                    })
+                    .collect()
+            }
+            FragmentSize::Bytes(max_bytes) => {
+                let mut fragments = Vec::new();
+                let mut first_line = start;
+                let mut bytes = 0;
+                for (idx, line) in file.content.iter().enumerate().take(end).skip(start) {
+                    // + 1 for the line break
+                    let line_bytes = line.line.len() + 1;
+                    if idx > first_line && bytes + line_bytes > max_bytes {
+                        fragments.push(Fragment {
+                            file: file.clone(),
+                            first_line,
+                            last_line: idx - 1,
+                            context_lines: 0,
+                            path_in_prompt: false,
+                            prompt_template: None,
+                        });
+                        first_line = idx;
+                        bytes = 0;
+                    }
+                    bytes += line_bytes;
+                }
+                if first_line < end {
+                    fragments.push(Fragment {
+                        file: file.clone(),
+                        first_line,
+                        last_line: end - 1,
+                        context_lines: 0,
+                        path_in_prompt: false,
+                        prompt_template: None,
+                    });
                 }
+                fragments
+            }
+            FragmentSize::WholeFile => (start < end)
+                .then(|| Fragment {
+                    file: file.clone(),
+                    first_line: start,
+                    last_line: end - 1,
+                    context_lines: 0,
+                    path_in_prompt: false,
+                    prompt_template: None,
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Consecutive fragments from `start` to `end` (exclusive), each starting at one of the
+    /// `starts`, preceded by one for the lines before the first of them.
+    fn split_at(file: &Arc<File>, start: usize, end: usize, starts: &[usize]) -> Vec<Fragment> {
+        std::iter::once(start)
+            .chain(starts.iter().copied())
+            .chain(std::iter::once(end))
+            .collect::<Vec<_>>()
+            .windows(2)
+            .filter(|bounds| bounds[0] < bounds[1])
+            .map(|bounds| Fragment {
+                file: file.clone(),
+                first_line: bounds[0],
+                last_line: bounds[1] - 1,
+                context_lines: 0,
+                path_in_prompt: false,
+                prompt_template: None,
             })
             .collect()
     }
+
+    fn apply_min_lines(fragments: Vec<Fragment>, min_lines: usize) -> Vec<Fragment> {
+        fragments
+            .into_iter()
+            .filter(|fragment| fragment.line_count() >= min_lines)
+            .collect()
+    }
 }
 
 impl Fragment {
-    fn content_iter(&self) -> impl Iterator<Item = &FileLine> {
+    pub fn with_context_lines(self, context_lines: usize) -> Self {
+        Self {
+            context_lines,
+            ..self
+        }
+    }
+
+    /// Prefixes the prompt with the path and line range of the fragment, see [`Fragment::prompt`].
+    pub fn with_path_in_prompt(self, path_in_prompt: bool) -> Self {
+        Self {
+            path_in_prompt,
+            ..self
+        }
+    }
+
+    /// Formats the prompt with a template instead, see [`Fragment::prompt`].
+    pub fn with_prompt_template(self, prompt_template: Option<Arc<str>>) -> Self {
+        Self {
+            prompt_template,
+            ..self
+        }
+    }
+
+    fn lines(&self, first_line: usize, last_line: usize) -> impl Iterator<Item = &FileLine> {
         self.file
             .content
             .iter()
-            .skip(self.first_line)
-            .take(self.last_line - self.first_line + 1)
+            .skip(first_line - self.file.first_line)
+            .take(last_line - first_line + 1)
+    }
+
+    fn context_first_line(&self) -> usize {
+        self.first_line
+            .saturating_sub(self.context_lines)
+            .max(self.file.first_line)
     }
+
+    fn context_last_line(&self) -> usize {
+        std::cmp::min(
+            self.last_line + self.context_lines,
+            (self.file.first_line + self.file.content.len()).saturating_sub(1),
+        )
+    }
+
     pub fn content(&self) -> String {
-        self.content_iter()
+        self.lines(self.first_line, self.last_line)
+            .map(|c| c.line.as_ref())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Content including the surrounding context lines, as sent to the model.
+    pub fn content_with_context(&self) -> String {
+        self.lines(self.context_first_line(), self.context_last_line())
             .map(|c| c.line.as_ref())
             .collect::<Vec<_>>()
             .join("\n")
     }
 
+    /// What the model is asked to evaluate: the content with context, optionally preceded by
+    /// the path and the 1-based line range of that content. A prompt template replaces this,
+    /// with `{code}`, `{path}` and `{lines}` filled in.
+    pub fn prompt(&self) -> String {
+        let lines = format!(
+            "{}-{}",
+            self.context_first_line() + 1,
+            self.context_last_line() + 1
+        );
+        fill_template(
+            prompt_template(self.path_in_prompt, self.prompt_template.as_deref()),
+            &[
+                ("code", &self.content_with_context()),
+                ("path", &self.file.path.display().to_string()),
+                ("lines", &lines),
+            ],
+        )
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.file.path
+    }
+
+    /// Name of the syntax detected for the file of the fragment.
+    pub fn language(&self) -> &str {
+        &self.file.language
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.last_line - self.first_line + 1
+    }
+
+    /// Zero based index of the first line of the fragment.
+    pub fn first_line(&self) -> usize {
+        self.first_line
+    }
+
+    /// Zero based index of the last line of the fragment, inclusive.
+    pub fn last_line(&self) -> usize {
+        self.last_line
+    }
+
     pub fn location(&self) -> String {
         format!("{}:{}", self.file.path.display(), self.first_line)
     }
 
+    /// Highlighted content with the surrounding context lines dimmed, plain if highlighting was
+    /// skipped.
     pub fn highlighted_content(&self) -> Vec<Line<'static>> {
-        self.content_iter()
-            .map(|c| c.highlighted_line.clone())
+        self.styled_content(|c| {
+            c.highlighted_line
+                .clone()
+                .unwrap_or_else(|| Line::raw(c.line.clone()))
+        })
+    }
+
+    /// Like [`Fragment::highlighted_content`], but without syntax highlighting.
+    pub fn plain_content(&self) -> Vec<Line<'static>> {
+        self.styled_content(|c| Line::raw(c.line.clone()))
+    }
+
+    /// Lines with context, the context lines dimmed.
+    fn styled_content(&self, line: impl Fn(&FileLine) -> Line<'static>) -> Vec<Line<'static>> {
+        let context_first_line = self.context_first_line();
+        self.lines(context_first_line, self.context_last_line())
+            .enumerate()
+            .map(|(idx, c)| {
+                let line_idx = context_first_line + idx;
+                if (self.first_line..=self.last_line).contains(&line_idx) {
+                    line(c)
+                } else {
+                    line(c).patch_style(Modifier::DIM)
+                }
+            })
             .collect::<Vec<_>>()
     }
 }
 
+/// Splits a `path:first-last` argument into the path and the 0-based line range, unless a file
+/// with the full name exists.
+pub fn split_line_range(file: &Path) -> (PathBuf, Option<(usize, usize)>) {
+    let whole = (file.to_path_buf(), None);
+    if file.exists() {
+        return whole;
+    }
+    let Some((path, range)) = file.to_str().and_then(|file| file.rsplit_once(':')) else {
+        return whole;
+    };
+    let Some((first, last)) = range.split_once('-') else {
+        return whole;
+    };
+    match (first.parse::<usize>(), last.parse::<usize>()) {
+        (Ok(first), Ok(last)) if first >= 1 && last >= first => {
+            (PathBuf::from(path), Some((first - 1, last - 1)))
+        }
+        _ => whole,
+    }
+}
+
+/// Template the prompt of a fragment is filled from, see [`Fragment::prompt`].
+pub fn prompt_template(path_in_prompt: bool, template: Option<&str>) -> &str {
+    match template {
+        Some(template) => template,
+        None if path_in_prompt => PATH_PROMPT_TEMPLATE,
+        None => "{code}",
+    }
+}
+
+/// Replaces each `{name}` in `template` by its value in a single pass, so placeholders inside
+/// the values are left alone. Unknown placeholders are kept as they are.
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = values.iter().find_map(|(name, value)| {
+            rest.strip_prefix('{')
+                .and_then(|r| r.strip_prefix(name))
+                .and_then(|r| r.strip_prefix('}'))
+                .map(|r| (value, r))
+        });
+        match value {
+            Some((value, r)) => {
+                result.push_str(value);
+                rest = r;
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Orders `fragments` file by file, in the order the files first appear, and by line within
+/// each file.
+pub fn group_by_file(fragments: &mut [Fragment]) {
+    let mut file_order = HashMap::new();
+    for fragment in fragments.iter() {
+        let next = file_order.len();
+        file_order
+            .entry(fragment.path().to_path_buf())
+            .or_insert(next);
+    }
+    fragments.sort_by_key(|fragment| (file_order[fragment.path()], fragment.first_line));
+}
+
+/// Orders `fragments` round-robin across files, the first fragment of each file, then the second
+/// one and so on, so every file makes progress early. Files and lines are ordered as in
+/// [`group_by_file`].
+pub fn interleave_files(fragments: &mut [Fragment]) {
+    group_by_file(fragments);
+    let mut file_order: HashMap<&Path, usize> = HashMap::new();
+    let mut file_counts: HashMap<&Path, usize> = HashMap::new();
+    let mut keyed: Vec<((usize, usize), Fragment)> = Vec::with_capacity(fragments.len());
+    for fragment in fragments.iter() {
+        let next = file_order.len();
+        let file = *file_order.entry(fragment.path()).or_insert(next);
+        let count = file_counts.entry(fragment.path()).or_default();
+        keyed.push(((*count, file), fragment.clone()));
+        *count += 1;
+    }
+    keyed.sort_by_key(|(key, _)| *key);
+    for (fragment, (_, interleaved)) in fragments.iter_mut().zip(keyed) {
+        *fragment = interleaved;
+    }
+}
+
+/// All files of `fragments` concatenated in order, each preceded by a `=== path ===` header.
+pub fn flatten(fragments: &[Fragment]) -> String {
+    let mut files: Vec<&Arc<File>> = Vec::new();
+    for fragment in fragments {
+        if !files.iter().any(|file| Arc::ptr_eq(file, &fragment.file)) {
+            files.push(&fragment.file);
+        }
+    }
+    files
+        .iter()
+        .map(|file| {
+            let content = file
+                .content
+                .iter()
+                .map(|c| c.line.as_ref())
+                .collect::<Vec<_>>()
+                .join("\n");
+            format!("=== {} ===\n{}", file.path.display(), content)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// A fragment chunked elsewhere, one line of a JSON lines input.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Chunk {
+    pub path: PathBuf,
+    /// Zero based index of the first line of `content` in the file.
+    pub first_line: usize,
+    pub content: String,
+}
+
+/// A single fragment of the whole `chunk`, highlighted as far as the syntax detected from its
+/// path allows. None if the content is empty.
+pub fn chunk_to_fragment(
+    chunk: Chunk,
+    options: &FragmentOptions,
+    theme: Theme,
+) -> crate::Result<Option<Fragment>> {
+    let mut file = File::from_content(chunk.path, &chunk.content, options, theme.into())?;
+    if file.content.is_empty() {
+        return Ok(None);
+    }
+    file.first_line = chunk.first_line;
+    Ok(Some(Fragment {
+        first_line: chunk.first_line,
+        last_line: chunk.first_line + file.content.len() - 1,
+        context_lines: 0,
+        path_in_prompt: false,
+        prompt_template: None,
+        file: Arc::new(file),
+    }))
+}
+
+/// Reads and splits `file`, a `path:first-last` suffix restricts the fragments to those lines.
 pub fn file_to_fragments<P: AsRef<Path>>(
     file: P,
-    lines_per_block: usize,
-    blocks_per_fragment: usize,
+    options: impl Into<FragmentOptions>,
     theme: Theme,
-) -> anyhow::Result<Vec<Fragment>> {
+) -> crate::Result<Vec<Fragment>> {
     let theme: SyntectTheme = theme.into();
-    Ok(File::read(file, theme)?.into_fragments(lines_per_block, blocks_per_fragment))
+    let options = options.into();
+    let (path, lines) = split_line_range(file.as_ref());
+    let range = lines.unwrap_or(WHOLE_FILE);
+    Ok(File::read(path, &options, theme)?.into_fragments(&options, &[range]))
+}
+
+/// Reads `file` and splits only the given line `ranges`, 0-based and inclusive.
+pub fn file_ranges_to_fragments<P: AsRef<Path>>(
+    file: P,
+    ranges: &[(usize, usize)],
+    options: impl Into<FragmentOptions>,
+    theme: Theme,
+) -> crate::Result<Vec<Fragment>> {
+    let theme: SyntectTheme = theme.into();
+    let options = options.into();
+    Ok(File::read(file, &options, theme)?.into_fragments(&options, ranges))
 }
 
 #[cfg(test)]
@@ -144,7 +780,14 @@ mod tests {
         let file_path = dir.path().join("sample.rs");
         std::fs::write(&file_path, "fn one() {}\nfn two() {}\nfn three() {}\n")?;
 
-        let fragments = file_to_fragments(&file_path, 2, 1, theme)?;
+        let fragments = file_to_fragments(
+            &file_path,
+            FragmentSize::Lines {
+                lines_per_block: 2,
+                blocks_per_fragment: 1,
+            },
+            theme,
+        )?;
 
         assert_eq!(fragments.len(), 2);
         assert_eq!(
@@ -152,6 +795,374 @@ mod tests {
             "fn one() {}\nfn two() {}\nfn three() {}"
         );
         assert_eq!(fragments[1].content(), "fn three() {}");
+        assert_eq!(fragments[0].language(), "Rust");
+        Ok(())
+    }
+
+    #[test]
+    fn file_to_fragments_splits_by_bytes() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("sample.rs");
+        std::fs::write(&file_path, "fn one() {}\nfn two() {}\nfn three() {}\n")?;
+
+        let fragments = file_to_fragments(&file_path, FragmentSize::Bytes(26), Theme::synthwave())?;
+
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[0].content(), "fn one() {}\nfn two() {}");
+        assert_eq!(fragments[1].content(), "fn three() {}");
+        assert_eq!(fragments[1].path(), file_path);
+        assert_eq!(
+            (fragments[1].first_line(), fragments[1].last_line()),
+            (2, 2)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn content_with_context_is_clamped_to_file() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("sample.rs");
+        std::fs::write(&file_path, "fn one() {}\nfn two() {}\nfn three() {}\n")?;
+
+        let fragments = file_to_fragments(&file_path, FragmentSize::Bytes(1), Theme::synthwave())?;
+        let fragment = fragments[1].clone().with_context_lines(5);
+
+        assert_eq!(fragment.content(), "fn two() {}");
+        assert_eq!(
+            fragment.content_with_context(),
+            "fn one() {}\nfn two() {}\nfn three() {}"
+        );
+        assert_eq!(fragment.highlighted_content().len(), 3);
+        assert_eq!(fragment.plain_content()[1].to_string(), "fn two() {}");
+        assert_eq!(fragment.prompt(), fragment.content_with_context());
+        assert_eq!(
+            fragment.with_path_in_prompt(true).prompt(),
+            format!(
+                "File: {} (lines 1-3)\nfn one() {{}}\nfn two() {{}}\nfn three() {{}}",
+                file_path.display()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn prompt_template_fills_placeholders_once() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("sample.rs");
+        std::fs::write(&file_path, "let s = \"{path}\";\n")?;
+
+        let fragments =
+            file_to_fragments(&file_path, FragmentSize::Bytes(100), Theme::synthwave())?;
+        let fragment = fragments[0]
+            .clone()
+            .with_prompt_template(Some("{path}:{lines} {other}\n```\n{code}\n```".into()));
+
+        assert_eq!(
+            fragment.prompt(),
+            format!(
+                "{}:1-1 {{other}}\n```\nlet s = \"{{path}}\";\n```",
+                file_path.display()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn min_lines_drops_short_fragments() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("sample.rs");
+        std::fs::write(&file_path, "fn one() {}\nfn two() {}\nfn three() {}\n")?;
+
+        let lines = FragmentOptions {
+            min_lines: 2,
+            ..FragmentSize::Lines {
+                lines_per_block: 2,
+                blocks_per_fragment: 1,
+            }
+            .into()
+        };
+        let fragments = file_to_fragments(&file_path, lines, Theme::synthwave())?;
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].line_count(), 3);
+
+        let bytes = FragmentOptions {
+            min_lines: 2,
+            ..FragmentSize::Bytes(26).into()
+        };
+        let fragments = file_to_fragments(&file_path, bytes, Theme::synthwave())?;
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].content(), "fn one() {}\nfn two() {}");
+
+        let short_first = FragmentOptions {
+            min_lines: 2,
+            ..FragmentSize::Bytes(12).into()
+        };
+        let fragments = file_to_fragments(&file_path, short_first, Theme::synthwave())?;
+        assert!(fragments.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn no_highlight_keeps_plain_lines() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("sample.rs");
+        std::fs::write(&file_path, "fn one() {}\n")?;
+        let options = FragmentOptions {
+            no_highlight: true,
+            ..FragmentSize::WholeFile.into()
+        };
+
+        let plain = file_to_fragments(&file_path, options, Theme::synthwave())?.remove(0);
+        let highlighted =
+            file_to_fragments(&file_path, FragmentSize::WholeFile, Theme::synthwave())?.remove(0);
+
+        assert_eq!(plain.highlighted_content(), plain.plain_content());
+        assert_ne!(
+            highlighted.highlighted_content(),
+            plain.highlighted_content()
+        );
+        assert_eq!(plain.content(), highlighted.content());
+        Ok(())
+    }
+
+    #[test]
+    fn syntax_overrides_and_plain_text_fallback() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let template = dir.path().join("sample.rs.in");
+        std::fs::write(&template, "fn one() {}\n")?;
+        let unknown = dir.path().join("sample.unknown-ext");
+        std::fs::write(&unknown, "fn one() {}\n")?;
+
+        let options = FragmentOptions {
+            syntax_overrides: vec![("rs.in".to_string(), "rs".to_string())],
+            ..FragmentSize::Bytes(100).into()
+        };
+        let fragments = file_to_fragments(&template, options.clone(), Theme::synthwave())?;
+        assert_eq!(fragments[0].language(), "Rust");
+        let fragments = file_to_fragments(&unknown, options, Theme::synthwave())?;
+        assert_eq!(fragments[0].language(), "Plain Text");
+        Ok(())
+    }
+
+    #[test]
+    fn file_to_fragments_restricts_to_line_range() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("sample.rs");
+        std::fs::write(
+            &file_path,
+            "fn one() {}\nfn two() {}\nfn three() {}\nfn four() {}\n",
+        )?;
+
+        let fragments = file_to_fragments(
+            format!("{}:2-3", file_path.display()),
+            FragmentSize::Bytes(1),
+            Theme::synthwave(),
+        )?;
+
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[0].path(), file_path);
+        assert_eq!(fragments[0].content(), "fn two() {}");
+        assert_eq!(fragments[1].content(), "fn three() {}");
+        assert_eq!(
+            (fragments[1].first_line(), fragments[1].last_line()),
+            (2, 2)
+        );
+
+        let clamped = file_to_fragments(
+            format!("{}:4-100", file_path.display()),
+            FragmentSize::Bytes(1),
+            Theme::synthwave(),
+        )?;
+        assert_eq!(clamped.len(), 1);
+        assert_eq!(clamped[0].content(), "fn four() {}");
+        Ok(())
+    }
+
+    #[test]
+    fn whole_file_is_a_single_fragment() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("sample.rs");
+        std::fs::write(&file_path, "fn one() {}\nfn two() {}\nfn three() {}\n")?;
+
+        let fragments = file_to_fragments(&file_path, FragmentSize::WholeFile, Theme::synthwave())?;
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(
+            (fragments[0].first_line(), fragments[0].last_line()),
+            (0, 2)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn flatten_concatenates_files_once() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a.rs");
+        let b = dir.path().join("b.rs");
+        std::fs::write(&a, "fn a1() {}\nfn a2() {}\n")?;
+        std::fs::write(&b, "fn b1() {}\n")?;
+        let size = FragmentSize::Lines {
+            lines_per_block: 1,
+            blocks_per_fragment: 1,
+        };
+        let mut fragments = file_to_fragments(&a, size, Theme::synthwave())?;
+        fragments.extend(file_to_fragments(&b, size, Theme::synthwave())?);
+
+        assert_eq!(
+            flatten(&fragments),
+            format!(
+                "=== {} ===\nfn a1() {{}}\nfn a2() {{}}\n\n=== {} ===\nfn b1() {{}}",
+                a.display(),
+                b.display()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn group_by_file_keeps_first_appearance_and_line_order() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("b_first.rs");
+        let b = dir.path().join("a_second.rs");
+        std::fs::write(&a, "fn a1() {}\nfn a2() {}\n")?;
+        std::fs::write(&b, "fn b1() {}\nfn b2() {}\n")?;
+        let size = FragmentSize::Lines {
+            lines_per_block: 1,
+            blocks_per_fragment: 1,
+        };
+        let a_fragments = file_to_fragments(&a, size, Theme::synthwave())?;
+        let b_fragments = file_to_fragments(&b, size, Theme::synthwave())?;
+        let mut fragments = vec![
+            a_fragments[1].clone(),
+            b_fragments[1].clone(),
+            a_fragments[0].clone(),
+            b_fragments[0].clone(),
+        ];
+
+        group_by_file(&mut fragments);
+
+        let locations: Vec<_> = fragments.iter().map(Fragment::location).collect();
+        assert_eq!(
+            locations,
+            [
+                format!("{}:0", a.display()),
+                format!("{}:1", a.display()),
+                format!("{}:0", b.display()),
+                format!("{}:1", b.display()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn interleave_files_alternates_between_files() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a.rs");
+        let b = dir.path().join("b.rs");
+        std::fs::write(&a, "fn a1() {}\nfn a2() {}\nfn a3() {}\n")?;
+        std::fs::write(&b, "fn b1() {}\n")?;
+        let mut fragments = file_to_fragments(&a, FragmentSize::Bytes(1), Theme::synthwave())?;
+        fragments.extend(file_to_fragments(
+            &b,
+            FragmentSize::Bytes(1),
+            Theme::synthwave(),
+        )?);
+
+        interleave_files(&mut fragments);
+
+        let contents: Vec<_> = fragments.iter().map(Fragment::content).collect();
+        assert_eq!(
+            contents,
+            ["fn a1() {}", "fn b1() {}", "fn a2() {}", "fn a3() {}"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn syntax_dir_adds_syntaxes() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let syntax_dir = dir.path().join("syntaxes");
+        std::fs::create_dir(&syntax_dir)?;
+        std::fs::write(
+            syntax_dir.join("gwt.sublime-syntax"),
+            "%YAML 1.2\n---\nname: Grepowski Test\nfile_extensions: [gwt]\nscope: source.gwt\ncontexts:\n  main:\n    - match: '\\bfoo\\b'\n      scope: keyword.control.gwt\n",
+        )?;
+        let file_path = dir.path().join("sample.gwt");
+        std::fs::write(&file_path, "foo bar\n")?;
+
+        let options = FragmentOptions {
+            syntax_dir: Some(syntax_dir.clone()),
+            ..FragmentSize::Bytes(100).into()
+        };
+        let fragments = file_to_fragments(&file_path, options, Theme::synthwave())?;
+        assert_eq!(fragments[0].language(), "Grepowski Test");
+        assert!(Arc::ptr_eq(
+            &syntax_set(Some(&syntax_dir))?,
+            &syntax_set(Some(&syntax_dir))?
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn by_symbol_starts_fragments_at_top_level_definitions() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("sample.rs");
+        std::fs::write(
+            &file_path,
+            "use std::fmt;\n\nfn one() {\n    fn inner() {}\n}\n\nstruct Two;\n\nimpl Two {\n    fn three() {}\n}\n",
+        )?;
+        let options = FragmentOptions {
+            by_symbol: true,
+            ..FragmentSize::WholeFile.into()
+        };
+
+        let fragments = file_to_fragments(&file_path, options.clone(), Theme::synthwave())?;
+        let bounds: Vec<_> = fragments
+            .iter()
+            .map(|fragment| (fragment.first_line(), fragment.last_line()))
+            .collect();
+        assert_eq!(bounds, vec![(0, 1), (2, 5), (6, 7), (8, 10)]);
+
+        let small = FragmentOptions {
+            by_symbol: true,
+            ..FragmentSize::Bytes(30).into()
+        };
+        let fragments = file_to_fragments(&file_path, small, Theme::synthwave())?;
+        let bounds: Vec<_> = fragments
+            .iter()
+            .map(|fragment| (fragment.first_line(), fragment.last_line()))
+            .collect();
+        assert_eq!(
+            bounds,
+            vec![(0, 1), (2, 3), (4, 5), (6, 7), (8, 9), (10, 10)]
+        );
+        assert!(
+            fragments
+                .iter()
+                .all(|fragment| fragment.content().len() < 30)
+        );
+
+        let python = dir.path().join("sample.py");
+        std::fs::write(
+            &python,
+            "import os\n\ndef one():\n    return 1\n\nclass Two:\n    x = 1\n",
+        )?;
+        let fragments = file_to_fragments(&python, options.clone(), Theme::synthwave())?;
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(fragments[2].content(), "class Two:\n    x = 1");
+
+        let plain = dir.path().join("sample.unknown-ext");
+        std::fs::write(&plain, "fn one() {}\nfn two() {}\n")?;
+        let fragments = file_to_fragments(&plain, options.clone(), Theme::synthwave())?;
+        assert_eq!(fragments.len(), 1);
+        let fragments = file_to_fragments(
+            &plain,
+            FragmentOptions {
+                size: FragmentSize::Bytes(1),
+                ..options
+            },
+            Theme::synthwave(),
+        )?;
+        assert_eq!(fragments.len(), 2);
         Ok(())
     }
 }
@@ -1,7 +1,301 @@
-use crate::fragment::Fragment;
+use crate::{ai_query::QueryResult, fragment::Fragment};
 
 #[derive(Debug, Clone)]
 pub struct FragmentEvaluation {
     pub fragment: Fragment,
     pub value: f32,
+    pub reason: Option<String>,
+    /// Standard deviation of the value across samples, if more than one was taken.
+    pub std_dev: Option<f32>,
+    /// Content of the model response, to see what the value was extracted from.
+    pub raw: Option<String>,
+    /// Value as returned by the model, if `value` was normalized, see [`normalize`].
+    pub raw_value: Option<f32>,
+    /// Change since the previous run in watch mode, see [`compare`].
+    pub change: Option<Change>,
+}
+
+/// How the evaluation of a fragment differs from the previous run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    /// No fragment with the same content or location was evaluated before.
+    New,
+    /// Difference of the value returned by the model.
+    Delta(f32),
+}
+
+impl Change {
+    pub fn label(self) -> String {
+        match self {
+            Self::New => "(new)".to_string(),
+            Self::Delta(delta) => format!("({:+.2})", delta),
+        }
+    }
+}
+
+impl FragmentEvaluation {
+    /// The value, followed by the standard deviation if there is one.
+    pub fn value_label(&self) -> String {
+        let label = match self.std_dev {
+            Some(std_dev) => format!("{:.3} ±{:.2}", self.value, std_dev),
+            None => format!("{:.3}", self.value),
+        };
+        match self.raw_value {
+            Some(raw_value) => format!("{} (raw {:.3})", label, raw_value),
+            None => label,
+        }
+    }
+
+    /// Replaces the value and everything derived from it by a new result for the same fragment.
+    pub fn update(&mut self, result: QueryResult) {
+        self.value = result.value;
+        self.raw_value = None;
+        self.reason = result.reason;
+        self.std_dev = result.std_dev;
+        self.raw = Some(result.raw);
+        self.change = None;
+    }
+
+    /// Location, value and reason as a JSON object, one line of the NDJSON output.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "path": self.fragment.path(),
+            "first_line": self.fragment.first_line(),
+            "last_line": self.fragment.last_line(),
+            "value": self.value,
+            "std_dev": self.std_dev,
+            "reason": self.reason,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    /// By descending value
+    Score,
+    /// By file path and line
+    Location,
+}
+
+impl SortBy {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Score => Self::Location,
+            Self::Location => Self::Score,
+        }
+    }
+
+    /// Highest scores first, locations in file order.
+    pub fn default_order(self) -> SortOrder {
+        match self {
+            Self::Score => SortOrder::Desc,
+            Self::Location => SortOrder::Asc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Desc,
+    Asc,
+}
+
+impl SortOrder {
+    pub fn reversed(self) -> Self {
+        match self {
+            Self::Desc => Self::Asc,
+            Self::Asc => Self::Desc,
+        }
+    }
+}
+
+pub fn sort(eval: &mut [FragmentEvaluation], sort_by: SortBy, sort_order: SortOrder) {
+    match sort_by {
+        SortBy::Score => sort_by_value(eval),
+        SortBy::Location => eval.sort_by(|a, b| {
+            (a.fragment.path(), a.fragment.first_line())
+                .cmp(&(b.fragment.path(), b.fragment.first_line()))
+        }),
+    }
+    if sort_order != sort_by.default_order() {
+        eval.reverse();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalize {
+    /// Rescale linearly so the lowest value is 0 and the highest is 1
+    MinMax,
+    /// Map the mean to 0.5 and three standard deviations either way to 0 and 1
+    ZScore,
+}
+
+/// Rescales the values of `eval` to span [0, 1], keeping the model values in `raw_value`.
+/// Values already normalized are rescaled from their raw value, so this can be repeated after
+/// a value changed.
+pub fn normalize(eval: &mut [FragmentEvaluation], method: Normalize) {
+    let raw_values: Vec<f32> = eval
+        .iter()
+        .map(|e| e.raw_value.unwrap_or(e.value))
+        .collect();
+    if raw_values.is_empty() {
+        return;
+    }
+    let rescale: Box<dyn Fn(f32) -> f32> = match method {
+        Normalize::MinMax => {
+            let min = raw_values.iter().copied().fold(f32::INFINITY, f32::min);
+            let max = raw_values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let range = max - min;
+            Box::new(move |v| if range > 0.0 { (v - min) / range } else { 0.5 })
+        }
+        Normalize::ZScore => {
+            let n = raw_values.len() as f32;
+            let mean = raw_values.iter().sum::<f32>() / n;
+            let std_dev = (raw_values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n).sqrt();
+            Box::new(move |v| {
+                if std_dev > 0.0 {
+                    (0.5 + (v - mean) / std_dev / 6.0).clamp(0.0, 1.0)
+                } else {
+                    0.5
+                }
+            })
+        }
+    };
+    for (e, raw_value) in eval.iter_mut().zip(raw_values) {
+        e.value = rescale(raw_value);
+        e.raw_value = Some(raw_value);
+    }
+}
+
+/// Sets the change of each of `eval` against the `previous` evaluations of the same files. Same
+/// content is matched first, so moved code is still compared, then the remaining fragments by
+/// location. Returns the number of previous fragments without a match.
+pub fn compare(eval: &mut [FragmentEvaluation], previous: &[FragmentEvaluation]) -> usize {
+    let mut matches: Vec<Option<usize>> = vec![None; eval.len()];
+    let mut matched = vec![false; previous.len()];
+    let same_content = |e: &FragmentEvaluation, p: &FragmentEvaluation| {
+        e.fragment.content() == p.fragment.content()
+    };
+    let same_location = |e: &FragmentEvaluation, p: &FragmentEvaluation| {
+        e.fragment.first_line() == p.fragment.first_line()
+    };
+    for same in [same_content, same_location] {
+        for (e, m) in eval
+            .iter()
+            .zip(matches.iter_mut())
+            .filter(|(_, m)| m.is_none())
+        {
+            *m = (0..previous.len()).find(|&idx| {
+                !matched[idx]
+                    && previous[idx].fragment.path() == e.fragment.path()
+                    && same(e, &previous[idx])
+            });
+            if let Some(idx) = *m {
+                matched[idx] = true;
+            }
+        }
+    }
+    for (e, m) in eval.iter_mut().zip(matches) {
+        e.change = Some(match m {
+            Some(idx) => {
+                let p = &previous[idx];
+                Change::Delta(e.raw_value.unwrap_or(e.value) - p.raw_value.unwrap_or(p.value))
+            }
+            None => Change::New,
+        });
+    }
+    matched.iter().filter(|matched| !**matched).count()
+}
+
+/// Sorts the evaluations by descending value.
+pub fn sort_by_value(eval: &mut [FragmentEvaluation]) {
+    eval.sort_by(|a, b| b.value.partial_cmp(&a.value).expect("Order expected"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::{FragmentSize, file_to_fragments};
+    use crate::theme::Theme;
+    use tempfile::tempdir;
+
+    fn evaluations(values: &[f32]) -> anyhow::Result<Vec<FragmentEvaluation>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("sample.rs");
+        evaluations_of(&path, &"fn a() {}\n".repeat(values.len()), values)
+    }
+
+    /// One fragment per line of `content`, written to `path`, with `values` in that order.
+    fn evaluations_of(
+        path: &std::path::Path,
+        content: &str,
+        values: &[f32],
+    ) -> anyhow::Result<Vec<FragmentEvaluation>> {
+        std::fs::write(path, content)?;
+        Ok(
+            file_to_fragments(path, FragmentSize::Bytes(1), Theme::synthwave())?
+                .into_iter()
+                .zip(values)
+                .map(|(fragment, &value)| FragmentEvaluation {
+                    fragment,
+                    value,
+                    reason: None,
+                    std_dev: None,
+                    raw: None,
+                    raw_value: None,
+                    change: None,
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn normalize_keeps_raw_values() -> anyhow::Result<()> {
+        let mut eval = evaluations(&[0.4, 0.5, 0.6])?;
+
+        normalize(&mut eval, Normalize::MinMax);
+        let values: Vec<_> = eval.iter().map(|e| e.value).collect();
+        assert!((values[0] - 0.0).abs() < 1e-6);
+        assert!((values[1] - 0.5).abs() < 1e-6);
+        assert!((values[2] - 1.0).abs() < 1e-6);
+        assert_eq!(eval[2].raw_value, Some(0.6));
+
+        // normalizing again starts from the raw values
+        normalize(&mut eval, Normalize::ZScore);
+        assert!((eval[1].value - 0.5).abs() < 1e-6);
+        assert!(eval[0].value < 0.5 && eval[2].value > 0.5);
+        assert_eq!(eval[0].raw_value, Some(0.4));
+        Ok(())
+    }
+
+    #[test]
+    fn compare_matches_content_then_location() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("sample.rs");
+        let previous =
+            evaluations_of(&path, "fn a() {}\nfn b() {}\nfn c() {}\n", &[0.1, 0.2, 0.3])?;
+        // x added, b edited in place, a moved down, c removed
+        let mut eval = evaluations_of(
+            &path,
+            "fn x() {}\nfn b2() {}\nfn a() {}\n",
+            &[0.5, 0.4, 0.2],
+        )?;
+
+        let removed = compare(&mut eval, &previous);
+
+        assert_eq!(removed, 1);
+        assert_eq!(eval[0].change, Some(Change::New));
+        assert_eq!(eval[1].change, Some(Change::Delta(0.4 - 0.2)));
+        assert_eq!(eval[2].change, Some(Change::Delta(0.2 - 0.1)));
+        assert_eq!(Change::Delta(0.1).label(), "(+0.10)");
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_equal_values_to_middle() -> anyhow::Result<()> {
+        let mut eval = evaluations(&[0.7, 0.7])?;
+        normalize(&mut eval, Normalize::MinMax);
+        assert!(eval.iter().all(|e| e.value == 0.5));
+        Ok(())
+    }
 }
@@ -0,0 +1,424 @@
+//! Core of grepowski: split source files into fragments and let a LLM score how strongly a
+//! question applies to each of them.
+//!
+//! ```no_run
+//! use grepowski::{ai_query::{AI, DefaultAiQueryConfig}, fragment::FragmentSize};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let ai = AI::new(
+//!     "my-model",
+//!     "http://127.0.0.1:8080/v1",
+//!     None,
+//!     None,
+//!     DefaultAiQueryConfig,
+//!     "Does this code allocate?",
+//! );
+//! let size = FragmentSize::Lines { lines_per_block: 10, blocks_per_fragment: 3 };
+//! for eval in grepowski::evaluate(["src/main.rs"], size, &ai).await? {
+//!     println!("{} {:.3}", eval.fragment.location(), eval.value);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    ai_query::{AI, QueryResult, Usage},
+    fragment::{Fragment, FragmentOptions},
+    fragment_evaluation::{FragmentEvaluation, Normalize},
+    theme::Theme,
+};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+pub use error::{Error, Result};
+
+pub mod ai_query;
+pub mod diff;
+pub mod error;
+pub mod export;
+pub mod fragment;
+pub mod fragment_evaluation;
+pub mod session;
+pub mod theme;
+
+/// A file that didn't yield any fragments.
+#[derive(Debug, Clone)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Number of skipped files per reason.
+pub fn skipped_counts(skipped: &[SkippedFile]) -> BTreeMap<&str, usize> {
+    let mut counts = BTreeMap::new();
+    for file in skipped {
+        *counts.entry(file.reason.as_str()).or_default() += 1;
+    }
+    counts
+}
+
+/// Reads and splits all `files`, files that can't be read are skipped.
+pub fn load_fragments<P: AsRef<Path>>(
+    files: impl IntoIterator<Item = P>,
+    fragment_options: impl Into<FragmentOptions>,
+    theme: Theme,
+) -> Vec<Fragment> {
+    load_fragments_with_skipped(files, fragment_options, theme).0
+}
+
+/// Like [`load_fragments`], but also returns the files that were skipped and why.
+pub fn load_fragments_with_skipped<P: AsRef<Path>>(
+    files: impl IntoIterator<Item = P>,
+    fragment_options: impl Into<FragmentOptions>,
+    theme: Theme,
+) -> (Vec<Fragment>, Vec<SkippedFile>) {
+    let fragment_options = fragment_options.into();
+    let mut fragments = Vec::new();
+    let mut skipped = Vec::new();
+    for file in files {
+        let path = file.as_ref().to_path_buf();
+        let result = fragment::file_to_fragments(&path, fragment_options.clone(), theme);
+        collect_fragments(path, result, &mut fragments, &mut skipped);
+    }
+    (fragments, skipped)
+}
+
+/// Reads the files changed by the unified `diff` and splits only their added lines, see
+/// [`diff::changed_ranges`].
+pub fn load_diff_fragments(
+    diff: &str,
+    fragment_options: impl Into<FragmentOptions>,
+    theme: Theme,
+) -> (Vec<Fragment>, Vec<SkippedFile>) {
+    let fragment_options = fragment_options.into();
+    let mut fragments = Vec::new();
+    let mut skipped = Vec::new();
+    for (path, ranges) in diff::changed_ranges(diff) {
+        let result =
+            fragment::file_ranges_to_fragments(&path, &ranges, fragment_options.clone(), theme);
+        collect_fragments(path, result, &mut fragments, &mut skipped);
+    }
+    (fragments, skipped)
+}
+
+/// Builds a fragment from each line of `jsonl`, a JSON object with the `path`, the zero based
+/// `first_line` and the `content` of a chunk made elsewhere, see [`fragment::chunk_to_fragment`].
+/// Chunks without content are skipped, lines that can't be parsed fail.
+pub fn load_jsonl_fragments(
+    jsonl: &str,
+    fragment_options: impl Into<FragmentOptions>,
+    theme: Theme,
+) -> Result<(Vec<Fragment>, Vec<SkippedFile>)> {
+    let fragment_options = fragment_options.into();
+    let mut fragments = Vec::new();
+    let mut skipped = Vec::new();
+    for (idx, line) in jsonl.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let chunk: fragment::Chunk = serde_json::from_str(line)
+            .map_err(|e| Error::Parse(format!("line {}: {}", idx + 1, e)))?;
+        let path = chunk.path.clone();
+        let result = fragment::chunk_to_fragment(chunk, &fragment_options, theme)
+            .map(|fragment| fragment.into_iter().collect());
+        collect_fragments(path, result, &mut fragments, &mut skipped);
+    }
+    Ok((fragments, skipped))
+}
+
+fn collect_fragments(
+    path: PathBuf,
+    result: Result<Vec<Fragment>>,
+    fragments: &mut Vec<Fragment>,
+    skipped: &mut Vec<SkippedFile>,
+) {
+    let reason = match result {
+        Ok(file_fragments) if file_fragments.is_empty() => "empty".to_string(),
+        Ok(file_fragments) => {
+            fragments.extend(file_fragments);
+            return;
+        }
+        Err(Error::Io(e)) => e.kind().to_string(),
+        Err(e) => e.to_string(),
+    };
+    skipped.push(SkippedFile { path, reason });
+}
+
+/// How [`gather`] queries the fragments.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GatherOptions {
+    /// Go on with the next fragments if a request fails, see [`GatherEvent::Failed`].
+    pub continue_on_error: bool,
+    /// Fragments evaluated in a single request, see [`AI::query_batch`].
+    pub batch_size: usize,
+    /// Query the fragments grouped by file in line order, see [`fragment::group_by_file`].
+    pub by_file: bool,
+    /// Query the fragments round-robin across files, see [`fragment::interleave_files`].
+    pub interleave_files: bool,
+    /// Normalization of the values once all fragments are evaluated.
+    pub normalize: Option<Normalize>,
+}
+
+/// Progress of [`gather`], in the order the fragments are queried.
+#[derive(Debug)]
+pub enum GatherEvent<'a> {
+    /// A request for these fragments is about to be sent.
+    Batch(&'a [Fragment]),
+    /// A fragment was evaluated, with the value returned by the model.
+    Evaluated(&'a FragmentEvaluation),
+    /// The request for a fragment failed.
+    Failed(&'a Fragment, &'a Error),
+}
+
+/// Lets `ai` evaluate all `fragments` and returns the evaluations sorted by descending value,
+/// with the usage of all requests. Partial responses are passed to `on_partial` while streaming,
+/// everything else to `on_event`, whose errors stop gathering.
+pub async fn gather<E: From<Error>>(
+    fragments: &[Fragment],
+    ai: &AI,
+    options: GatherOptions,
+    mut on_partial: impl FnMut(&str),
+    mut on_event: impl AsyncFnMut(GatherEvent<'_>) -> std::result::Result<(), E>,
+) -> std::result::Result<(Vec<FragmentEvaluation>, Usage), E> {
+    let mut fragments = fragments.to_vec();
+    if options.by_file {
+        fragment::group_by_file(&mut fragments);
+    } else if options.interleave_files {
+        fragment::interleave_files(&mut fragments);
+    }
+    let mut eval = Vec::new();
+    let mut usage = Usage::default();
+    for batch in fragments.chunks(options.batch_size.max(1)) {
+        on_event(GatherEvent::Batch(batch)).await?;
+        let result = match batch {
+            [fragment] => ai
+                .query_with_progress(fragment.prompt(), &mut on_partial)
+                .await
+                .map(|result| vec![result]),
+            _ => {
+                let codes: Vec<_> = batch.iter().map(Fragment::prompt).collect();
+                ai.query_batch_with_progress(&codes, &mut on_partial).await
+            }
+        };
+        let results = match result {
+            Ok(results) => results,
+            Err(e) => {
+                for fragment in batch {
+                    on_event(GatherEvent::Failed(fragment, &e)).await?;
+                }
+                if !options.continue_on_error {
+                    return Err(e.into());
+                }
+                continue;
+            }
+        };
+        for (fragment, result) in batch.iter().zip(results) {
+            let QueryResult {
+                value,
+                reason,
+                usage: query_usage,
+                std_dev,
+                raw,
+            } = result;
+            usage = usage + query_usage.unwrap_or_default();
+            let evaluation = FragmentEvaluation {
+                fragment: fragment.clone(),
+                value,
+                reason,
+                std_dev,
+                raw: Some(raw),
+                raw_value: None,
+                change: None,
+            };
+            on_event(GatherEvent::Evaluated(&evaluation)).await?;
+            eval.push(evaluation);
+        }
+    }
+
+    fragment_evaluation::sort_by_value(&mut eval);
+    if let Some(method) = options.normalize {
+        fragment_evaluation::normalize(&mut eval, method);
+    }
+    Ok((eval, usage))
+}
+
+/// Lets `ai` evaluate each fragment and returns the evaluations sorted by descending value, see
+/// [`gather`] for more options.
+pub async fn evaluate_fragments(
+    fragments: impl AsRef<[Fragment]>,
+    ai: &AI,
+) -> Result<Vec<FragmentEvaluation>> {
+    let (eval, _) = gather(
+        fragments.as_ref(),
+        ai,
+        GatherOptions::default(),
+        |_| {},
+        async |_| Ok::<_, Error>(()),
+    )
+    .await?;
+    Ok(eval)
+}
+
+/// Reads and splits all `files` and lets `ai` evaluate the resulting fragments, see
+/// [`load_fragments`] and [`evaluate_fragments`].
+pub async fn evaluate<P: AsRef<Path>>(
+    files: impl IntoIterator<Item = P>,
+    fragment_options: impl Into<FragmentOptions>,
+    ai: &AI,
+) -> Result<Vec<FragmentEvaluation>> {
+    let fragments = load_fragments(files, fragment_options, Theme::synthwave());
+    evaluate_fragments(fragments, ai).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::FragmentSize;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn gather_reports_batches_and_failures() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let code = dir.path().join("code.rs");
+        std::fs::write(&code, "fn one() {}\nfn two() {}\nfn three() {}\n")?;
+        let size = FragmentSize::Lines {
+            lines_per_block: 1,
+            blocks_per_fragment: 1,
+        };
+        let fragments = load_fragments([&code], size, Theme::synthwave());
+        // nothing listens on the port of a dropped listener
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let ai = AI::new(
+            "m",
+            format!("http://{}/v1", unreachable),
+            None,
+            None,
+            crate::ai_query::DefaultAiQueryConfig,
+            "Q?",
+        );
+
+        let mut events = Vec::new();
+        let options = GatherOptions {
+            continue_on_error: true,
+            batch_size: 2,
+            ..GatherOptions::default()
+        };
+        let (eval, usage) = gather(
+            &fragments,
+            &ai,
+            options,
+            |_| {},
+            async |event| {
+                events.push(match event {
+                    GatherEvent::Batch(batch) => format!("batch {}", batch.len()),
+                    GatherEvent::Evaluated(eval) => eval.fragment.location(),
+                    GatherEvent::Failed(fragment, _) => format!("failed {}", fragment.first_line()),
+                });
+                Ok::<_, Error>(())
+            },
+        )
+        .await?;
+        assert!(eval.is_empty());
+        assert_eq!(usage, Usage::default());
+        assert_eq!(
+            events,
+            ["batch 2", "failed 0", "failed 1", "batch 1", "failed 2"]
+        );
+
+        let mut batches = 0;
+        let options = GatherOptions {
+            batch_size: 2,
+            ..GatherOptions::default()
+        };
+        let result = gather(
+            &fragments,
+            &ai,
+            options,
+            |_| {},
+            async |event| {
+                batches += matches!(event, GatherEvent::Batch(_)) as usize;
+                Ok::<_, Error>(())
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Network(_))));
+        assert_eq!(batches, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn load_fragments_reports_skipped_files() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let code = dir.path().join("code.rs");
+        let empty = dir.path().join("empty.rs");
+        let missing = dir.path().join("missing.rs");
+        std::fs::write(&code, "fn one() {}\n")?;
+        std::fs::write(&empty, "")?;
+
+        let (fragments, skipped) = load_fragments_with_skipped(
+            [&code, &empty, &missing],
+            FragmentSize::Bytes(100),
+            Theme::synthwave(),
+        );
+
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(skipped[0].path, empty);
+        assert_eq!(skipped[0].reason, "empty");
+        assert_eq!(skipped[1].path, missing);
+        assert_eq!(
+            skipped_counts(&skipped).get("entity not found").copied(),
+            Some(1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn files_with_too_many_lines_are_skipped() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let short = dir.path().join("short.rs");
+        let long = dir.path().join("long.rs");
+        std::fs::write(&short, "fn one() {}\nfn two() {}\n")?;
+        std::fs::write(&long, "fn one() {}\nfn two() {}\nfn three() {}\n")?;
+
+        let options = FragmentOptions {
+            max_lines: Some(2),
+            ..FragmentSize::WholeFile.into()
+        };
+        let (fragments, skipped) =
+            load_fragments_with_skipped([&short, &long], options, Theme::synthwave());
+
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].path, long);
+        assert_eq!(skipped[0].reason, "more than 2 lines");
+        Ok(())
+    }
+
+    #[test]
+    fn load_jsonl_fragments_keeps_input_locations() -> anyhow::Result<()> {
+        let jsonl = concat!(
+            r#"{"path": "src/a.rs", "first_line": 41, "content": "fn one() {}\nfn two() {}"}"#,
+            "\n\n",
+            r#"{"path": "src/b.rs", "first_line": 0, "content": ""}"#,
+            "\n",
+        );
+
+        let (fragments, skipped) =
+            load_jsonl_fragments(jsonl, FragmentSize::WholeFile, Theme::synthwave())?;
+
+        assert_eq!(fragments.len(), 1);
+        let fragment = fragments[0].clone().with_context_lines(3);
+        assert_eq!(fragment.location(), "src/a.rs:41");
+        assert_eq!((fragment.first_line(), fragment.last_line()), (41, 42));
+        assert_eq!(fragment.language(), "Rust");
+        assert_eq!(fragment.content_with_context(), "fn one() {}\nfn two() {}");
+        assert_eq!(skipped[0].reason, "empty");
+
+        let error = load_jsonl_fragments("{}", FragmentSize::WholeFile, Theme::synthwave());
+        assert!(matches!(error, Err(Error::Parse(_))));
+        Ok(())
+    }
+}
@@ -1,42 +1,174 @@
-use crate::{
-    ai_query::{AI, DefaultAiQueryConfig},
-    fragment::Fragment,
-    fragment_evaluation::FragmentEvaluation,
-    tui::{Nav, Theme, TuiEvent},
+use crate::tui::{
+    EffectOptions, Nav, QUIT_CONFIRM_WINDOW, Search, TuiEvent, TuiOptions, TuiRequest,
 };
 use clap::CommandFactory;
-use crossterm::event::KeyEventKind;
+use crossterm::event::{KeyEventKind, KeyModifiers};
 use futures_util::{FutureExt, StreamExt};
-use tokio::{select, sync::mpsc::Sender};
+use grepowski::{
+    GatherEvent, SkippedFile,
+    ai_query::{self, AI, AiQueryConfig, DefaultAiQueryConfig, SchemaAiQueryConfig, Usage},
+    fragment::{self, Fragment, FragmentOptions, FragmentSize},
+    fragment_evaluation::{self, FragmentEvaluation},
+    session::Session,
+    theme::{self, SyntaxColors, Theme},
+};
+use std::{
+    cell::Cell,
+    io::{LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+use tokio::{
+    select,
+    sync::mpsc::{Sender, UnboundedReceiver},
+};
 
-mod ai_query;
 mod args;
-mod fragment;
-mod fragment_evaluation;
+mod bench;
 mod tui;
+mod validate;
+mod watch;
+
+#[derive(Debug)]
+struct GatherOptions {
+    query: grepowski::GatherOptions,
+    output: Option<ResultOutput>,
+    /// Chart of the values for the initial question, written after gathering.
+    chart: Option<ChartExport>,
+}
+
+/// Path and colors of the value history chart, see [`grepowski::export::svg_chart`].
+#[derive(Debug)]
+struct ChartExport {
+    path: String,
+    theme: Theme,
+}
+
+/// NDJSON file the evaluations are written to while gathering, flushed after every line so it
+/// can be followed and survives a crash. An HTML page is only written at the end instead.
+#[derive(Debug)]
+struct ResultOutput {
+    path: String,
+    writer: LineWriter<std::fs::File>,
+    sort: bool,
+    html: Option<HtmlExport>,
+}
+
+/// Header and colors of the HTML page, see [`grepowski::export::html`].
+#[derive(Debug)]
+struct HtmlExport {
+    question: String,
+    model: String,
+    theme: Theme,
+}
+
+impl ResultOutput {
+    fn create(path: String, sort: bool) -> anyhow::Result<Self> {
+        let file = std::fs::File::create(&path)
+            .map_err(|e| anyhow::anyhow!("error creating {}: {}", path, e))?;
+        Ok(Self {
+            path,
+            writer: LineWriter::new(file),
+            sort,
+            html: None,
+        })
+    }
+
+    fn with_html(self, html: Option<HtmlExport>) -> Self {
+        Self { html, ..self }
+    }
+
+    fn write(&mut self, eval: &FragmentEvaluation) -> anyhow::Result<()> {
+        if self.html.is_some() {
+            return Ok(());
+        }
+        writeln!(self.writer, "{}", eval.to_json())?;
+        Ok(())
+    }
+
+    /// Writes the HTML page, or replaces the file with the sorted evaluations if requested.
+    fn finish(mut self, eval: &[FragmentEvaluation]) -> anyhow::Result<()> {
+        if let Some(html) = &self.html {
+            let page = grepowski::export::html(&html.question, &html.model, eval, html.theme);
+            self.writer.write_all(page.as_bytes())?;
+            return Ok(());
+        }
+        if !self.sort {
+            return Ok(());
+        }
+        let mut sorted = Self::create(self.path, false)?;
+        for eval in eval {
+            sorted.write(eval)?;
+        }
+        Ok(())
+    }
+}
 
 async fn gather_data(
     fragments: impl AsRef<[Fragment]>,
     tx_tui: &Sender<TuiEvent>,
-    ai: AI,
+    ai: &AI,
+    usage: &Cell<Usage>,
+    options: &mut GatherOptions,
 ) -> anyhow::Result<Vec<FragmentEvaluation>> {
-    let mut eval = Vec::new();
-    for fragment in fragments.as_ref() {
-        tx_tui
-            .send(TuiEvent::GatherNextFragment(fragment.clone()))
-            .await?;
-        tx_tui.send(TuiEvent::Render).await?;
-        let value = ai.query(fragment.content()).await?;
-        tx_tui.send(TuiEvent::GatherNextValue(value)).await?;
-        tx_tui.send(TuiEvent::GatherIncrementCount).await?;
-        eval.push(FragmentEvaluation {
-            fragment: fragment.clone(),
-            value,
-        });
-    }
+    // only the results for the initial question are written
+    let mut output = options.output.take();
+    let chart = options.chart.take();
+    let continue_on_error = options.query.continue_on_error;
+    // as returned by the model, the output keeps them also if the results are normalized
+    let mut written = Vec::new();
+    let mut values = Vec::new();
+    // partial responses are cumulative, so dropping some when the tui lags is fine
+    let on_partial = |partial: &str| {
+        let _ = tx_tui.try_send(TuiEvent::GatherPartialResponse(partial.to_string()));
+    };
+    let on_event = async |event: GatherEvent<'_>| {
+        match event {
+            GatherEvent::Batch(batch) => {
+                tx_tui
+                    .send(TuiEvent::GatherNextFragment(batch[0].clone()))
+                    .await?;
+                tx_tui.send(TuiEvent::Render).await?;
+            }
+            GatherEvent::Evaluated(evaluation) => {
+                tx_tui
+                    .send(TuiEvent::GatherNextValue(evaluation.value))
+                    .await?;
+                tx_tui.send(TuiEvent::GatherIncrementCount).await?;
+                values.push(evaluation.value);
+                if let Some(output) = &mut output {
+                    output.write(evaluation)?;
+                    written.push(evaluation.clone());
+                }
+            }
+            GatherEvent::Failed(fragment, e) => {
+                tx_tui
+                    .send(TuiEvent::GatherFailure(fragment.location(), e.to_string()))
+                    .await?;
+                if continue_on_error {
+                    tx_tui.send(TuiEvent::GatherIncrementCount).await?;
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    let (eval, gather_usage) =
+        grepowski::gather(fragments.as_ref(), ai, options.query, on_partial, on_event).await?;
+    usage.set(usage.get() + gather_usage);
     tx_tui.send(TuiEvent::Render).await?;
 
-    eval.sort_by(|a, b| b.value.partial_cmp(&a.value).expect("Order expected"));
+    if let Some(output) = output {
+        fragment_evaluation::sort_by_value(&mut written);
+        output.finish(&written)?;
+    }
+    if let Some(chart) = chart {
+        std::fs::write(
+            &chart.path,
+            grepowski::export::svg_chart(&values, chart.theme),
+        )
+        .map_err(|e| anyhow::anyhow!("error writing {}: {}", chart.path, e))?;
+    }
 
     Ok(eval)
 }
@@ -47,21 +179,144 @@ async fn finish(eval: Vec<FragmentEvaluation>, tx_tui: &Sender<TuiEvent>) -> any
     Ok(())
 }
 
+/// Requeries and explains fragments until a new question is asked or files changed, which is
+/// returned. The requeried results are also applied to `eval`.
+async fn requery(
+    rx_request: &mut UnboundedReceiver<TuiRequest>,
+    tx_tui: &Sender<TuiEvent>,
+    ai: &AI,
+    usage: &Cell<Usage>,
+    eval: &mut [FragmentEvaluation],
+) -> anyhow::Result<Option<TuiRequest>> {
+    while let Some(request) = rx_request.recv().await {
+        let fragment = match request {
+            TuiRequest::Requery(fragment) => fragment,
+            TuiRequest::Explain(fragment) => {
+                let result = match ai.explain(fragment.prompt()).await {
+                    Ok((explanation, explain_usage)) => {
+                        usage.set(usage.get() + explain_usage.unwrap_or_default());
+                        Ok(explanation)
+                    }
+                    Err(e) => Err(e.to_string()),
+                };
+                tx_tui
+                    .send(TuiEvent::Explained {
+                        location: fragment.location(),
+                        result,
+                    })
+                    .await?;
+                tx_tui.send(TuiEvent::Render).await?;
+                continue;
+            }
+            _ => return Ok(Some(request)),
+        };
+        let result = match ai.query(fragment.prompt()).await {
+            Ok(result) => {
+                usage.set(usage.get() + result.usage.unwrap_or_default());
+                if let Some(e) = eval
+                    .iter_mut()
+                    .find(|e| e.fragment.location() == fragment.location())
+                {
+                    e.update(result.clone());
+                }
+                Ok(result)
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        tx_tui
+            .send(TuiEvent::Requeried {
+                location: fragment.location(),
+                result,
+            })
+            .await?;
+        tx_tui.send(TuiEvent::Render).await?;
+    }
+    Ok(None)
+}
+
 async fn main_flow(
-    fragments: impl AsRef<[Fragment]>,
+    mut fragments: Vec<Fragment>,
     tx_tui: &Sender<TuiEvent>,
-    ai: AI,
+    mut rx_request: UnboundedReceiver<TuiRequest>,
+    ai: &mut AI,
+    usage: &Cell<Usage>,
+    displaying: &Cell<bool>,
+    mut gather_options: GatherOptions,
 ) -> anyhow::Result<()> {
-    finish(gather_data(fragments, tx_tui, ai).await?, tx_tui).await
+    let mut eval = gather_data(&fragments, tx_tui, ai, usage, &mut gather_options).await?;
+    // fragments of changed files that are gone since the previous run
+    let mut removed = 0;
+    loop {
+        finish(eval.clone(), tx_tui).await?;
+        if removed > 0 {
+            tx_tui.send(TuiEvent::Removed(removed)).await?;
+            tx_tui.send(TuiEvent::Render).await?;
+        }
+        removed = 0;
+        displaying.set(true);
+        let request = requery(&mut rx_request, tx_tui, ai, usage, &mut eval).await?;
+        displaying.set(false);
+        match request {
+            Some(TuiRequest::Ask(question)) => {
+                // the fragments are already read and highlighted, only gathering is repeated
+                ai.set_question(question);
+                tx_tui
+                    .send(TuiEvent::SwitchToGatherData(fragments.len()))
+                    .await?;
+                eval = gather_data(&fragments, tx_tui, ai, usage, &mut gather_options).await?;
+            }
+            Some(TuiRequest::Reload {
+                paths,
+                fragments: changed,
+            }) => {
+                // only the fragments of the changed files are evaluated again
+                fragments.retain(|fragment| !paths.iter().any(|path| path == fragment.path()));
+                let previous;
+                (previous, eval) = eval
+                    .into_iter()
+                    .partition(|e| paths.iter().any(|path| path == e.fragment.path()));
+                // changes are shown for the latest run only
+                for e in &mut eval {
+                    e.change = None;
+                }
+                tx_tui
+                    .send(TuiEvent::SwitchToGatherData(changed.len()))
+                    .await?;
+                let mut changed_eval =
+                    gather_data(&changed, tx_tui, ai, usage, &mut gather_options).await?;
+                removed = fragment_evaluation::compare(&mut changed_eval, &previous);
+                eval.extend(changed_eval);
+                if let Some(method) = gather_options.query.normalize {
+                    fragment_evaluation::normalize(&mut eval, method);
+                }
+                fragments.extend(changed);
+            }
+            Some(TuiRequest::Requery(_) | TuiRequest::Explain(_)) | None => return Ok(()),
+        }
+    }
 }
 
 async fn input_and_main_flow(
-    fragments: impl AsRef<[Fragment]>,
+    fragments: Vec<Fragment>,
     tx_tui: &Sender<TuiEvent>,
-    ai: AI,
+    rx_request: UnboundedReceiver<TuiRequest>,
+    ai: &mut AI,
+    usage: &Cell<Usage>,
+    confirm_quit: bool,
+    gather_options: GatherOptions,
 ) -> anyhow::Result<()> {
-    let main = main_flow(fragments, tx_tui, ai).fuse();
-    let input = process_input(tx_tui);
+    let displaying = Cell::new(false);
+    let main = main_flow(
+        fragments,
+        tx_tui,
+        rx_request,
+        ai,
+        usage,
+        &displaying,
+        gather_options,
+    )
+    .fuse();
+    let input = process_input(tx_tui, &displaying, confirm_quit);
 
     futures::pin_mut!(main, input);
     let result = loop {
@@ -76,61 +331,205 @@ async fn input_and_main_flow(
                 // when input is done, we can return
                 break input_result;
             }
+            signal_result = tokio::signal::ctrl_c() => {
+                // SIGINT from outside the tui, e.g. kill -INT, quits like q does
+                break signal_result.map_err(anyhow::Error::from);
+            }
         }
     };
     tx_tui.send(TuiEvent::Quit).await?;
     result
 }
 
-async fn process_input(tx_tui: &Sender<TuiEvent>) -> anyhow::Result<()> {
+async fn process_input(
+    tx_tui: &Sender<TuiEvent>,
+    displaying: &Cell<bool>,
+    confirm_quit: bool,
+) -> anyhow::Result<()> {
     enum RenderDecision {
         DoRender,
         DontRender,
     }
 
     let mut reader = crossterm::event::EventStream::new();
+    let mut searching = false;
+    let mut asking = false;
+    let mut quit_requested: Option<Instant> = None;
 
     loop {
         match reader.next().await {
             Some(Ok(event)) => match event {
-                crossterm::event::Event::Key(key) => {
-                    if key.kind == KeyEventKind::Press {
-                        let render_decision = match key.code {
-                            crossterm::event::KeyCode::Char('q')
-                            | crossterm::event::KeyCode::Esc => {
-                                break;
-                            }
-                            crossterm::event::KeyCode::Up => {
-                                tx_tui.send(TuiEvent::Nav(Nav::Up)).await?;
-                                RenderDecision::DoRender
-                            }
-                            crossterm::event::KeyCode::Down => {
-                                tx_tui.send(TuiEvent::Nav(Nav::Down)).await?;
-                                RenderDecision::DoRender
-                            }
-                            crossterm::event::KeyCode::PageUp => {
-                                tx_tui.send(TuiEvent::Nav(Nav::PageUp)).await?;
-                                RenderDecision::DoRender
-                            }
-                            crossterm::event::KeyCode::PageDown => {
-                                tx_tui.send(TuiEvent::Nav(Nav::PageDown)).await?;
-                                RenderDecision::DoRender
-                            }
-                            crossterm::event::KeyCode::Home => {
-                                tx_tui.send(TuiEvent::Nav(Nav::Home)).await?;
-                                RenderDecision::DoRender
-                            }
-                            crossterm::event::KeyCode::End => {
-                                tx_tui.send(TuiEvent::Nav(Nav::End)).await?;
-                                RenderDecision::DoRender
-                            }
-                            _ => RenderDecision::DontRender,
-                        };
-                        if matches!(render_decision, RenderDecision::DoRender) {
-                            tx_tui.send(TuiEvent::Render).await?;
+                crossterm::event::Event::Key(key)
+                    if key.kind == KeyEventKind::Press && (searching || asking) =>
+                {
+                    let search = match key.code {
+                        crossterm::event::KeyCode::Char(c) => Some(Search::Input(c)),
+                        crossterm::event::KeyCode::Backspace => Some(Search::Backspace),
+                        crossterm::event::KeyCode::Enter => Some(Search::Confirm),
+                        crossterm::event::KeyCode::Esc => Some(Search::Cancel),
+                        _ => None,
+                    };
+                    if let Some(search) = search {
+                        let editing = !matches!(search, Search::Confirm | Search::Cancel);
+                        let event = if asking {
+                            TuiEvent::Question(search)
+                        } else {
+                            TuiEvent::Search(search)
                         };
+                        (searching, asking) = (editing && searching, editing && asking);
+                        tx_tui.send(event).await?;
+                        tx_tui.send(TuiEvent::Render).await?;
                     }
                 }
+                crossterm::event::Event::Key(key) if key.kind == KeyEventKind::Press => {
+                    let quit_pending = quit_requested
+                        .is_some_and(|requested| requested.elapsed() < QUIT_CONFIRM_WINDOW);
+                    let render_decision = match key.code {
+                        // raw mode turns Ctrl-C into a key press instead of SIGINT
+                        crossterm::event::KeyCode::Char('c')
+                            if key.modifiers.contains(KeyModifiers::CONTROL) =>
+                        {
+                            break;
+                        }
+                        crossterm::event::KeyCode::Char('q') | crossterm::event::KeyCode::Esc => {
+                            if !confirm_quit || displaying.get() || quit_pending {
+                                break;
+                            }
+                            quit_requested = Some(Instant::now());
+                            tx_tui.send(TuiEvent::ConfirmQuit).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('y') if quit_pending => {
+                            break;
+                        }
+                        crossterm::event::KeyCode::Up => {
+                            tx_tui.send(TuiEvent::Nav(Nav::Up)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Down => {
+                            tx_tui.send(TuiEvent::Nav(Nav::Down)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Left | crossterm::event::KeyCode::Char('h') => {
+                            tx_tui.send(TuiEvent::Nav(Nav::Left)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Right | crossterm::event::KeyCode::Char('l') => {
+                            tx_tui.send(TuiEvent::Nav(Nav::Right)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::PageUp => {
+                            tx_tui.send(TuiEvent::Nav(Nav::PageUp)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::PageDown => {
+                            tx_tui.send(TuiEvent::Nav(Nav::PageDown)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Home => {
+                            tx_tui.send(TuiEvent::Nav(Nav::Home)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::End => {
+                            tx_tui.send(TuiEvent::Nav(Nav::End)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('/') if displaying.get() => {
+                            searching = true;
+                            tx_tui.send(TuiEvent::Search(Search::Start)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('a') if displaying.get() => {
+                            asking = true;
+                            tx_tui.send(TuiEvent::Question(Search::Start)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('e') => {
+                            tx_tui.send(TuiEvent::Explain).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('r') => {
+                            tx_tui.send(TuiEvent::Requery).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('s') => {
+                            tx_tui.send(TuiEvent::ToggleSortBy).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('o') => {
+                            tx_tui.send(TuiEvent::ToggleSortOrder).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('w') => {
+                            tx_tui.send(TuiEvent::ToggleWrap).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('t') => {
+                            tx_tui.send(TuiEvent::Nav(Nav::Best)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('b') => {
+                            tx_tui.send(TuiEvent::Nav(Nav::Worst)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('[') => {
+                            tx_tui.send(TuiEvent::Nav(Nav::PrevFile)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char(']') => {
+                            tx_tui.send(TuiEvent::Nav(Nav::NextFile)).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('i') => {
+                            tx_tui.send(TuiEvent::ToggleSummary).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('d') => {
+                            tx_tui.send(TuiEvent::ToggleRaw).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('x') => {
+                            tx_tui.send(TuiEvent::TogglePlain).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('p') => {
+                            tx_tui.send(TuiEvent::ToggleListOnly).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('m') => {
+                            tx_tui.send(TuiEvent::ToggleThreshold).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('f') => {
+                            tx_tui.send(TuiEvent::ToggleFullHistory).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('z') => {
+                            tx_tui.send(TuiEvent::ToggleCodeOnly).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('g') => {
+                            tx_tui.send(TuiEvent::ToggleGrouped).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Enter => {
+                            tx_tui.send(TuiEvent::ToggleExpanded).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Delete => {
+                            tx_tui.send(TuiEvent::HideSelected).await?;
+                            RenderDecision::DoRender
+                        }
+                        crossterm::event::KeyCode::Char('u') => {
+                            tx_tui.send(TuiEvent::Unhide).await?;
+                            RenderDecision::DoRender
+                        }
+                        _ => RenderDecision::DontRender,
+                    };
+                    if matches!(render_decision, RenderDecision::DoRender) {
+                        tx_tui.send(TuiEvent::Render).await?;
+                    };
+                }
                 crossterm::event::Event::Resize(_, _) => {
                     tx_tui.send(TuiEvent::Render).await?;
                 }
@@ -148,53 +547,321 @@ async fn process_input(tx_tui: &Sender<TuiEvent>) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn report_usage(usage: Usage, price_per_1k: Option<f64>) {
+    if usage.total_tokens() == 0 {
+        return;
+    }
+    println!(
+        "Tokens used: {} prompt, {} completion, {} total",
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        usage.total_tokens()
+    );
+    if let Some(price_per_1k) = price_per_1k {
+        println!(
+            "Estimated cost: {:.4}",
+            usage.total_tokens() as f64 / 1000.0 * price_per_1k
+        );
+    }
+}
+
+fn report_skipped(skipped: &[SkippedFile], verbose: bool) {
+    if skipped.is_empty() {
+        return;
+    }
+    eprintln!("Skipped {} file(s):", skipped.len());
+    for (reason, count) in grepowski::skipped_counts(skipped) {
+        eprintln!("  {}: {}", reason, count);
+    }
+    if verbose {
+        for file in skipped {
+            eprintln!("  {} ({})", file.path.display(), file.reason);
+        }
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
     let cli = args::parse();
 
     match cli.command {
-        args::Command::Completions { shell } => {
+        args::Command::Completions { shell, out } => {
             let mut command = args::Cli::command();
             let bin_name = command.get_name().to_string();
-            clap_complete::generate(shell, &mut command, bin_name, &mut std::io::stdout());
+            match out {
+                Some(out) => {
+                    let out = std::path::Path::new(&out);
+                    if let Some(parent) = out.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    let mut file = std::fs::File::create(out)
+                        .map_err(|e| anyhow::anyhow!("error creating {}: {}", out.display(), e))?;
+                    clap_complete::generate(shell, &mut command, bin_name, &mut file);
+                }
+                None => {
+                    clap_complete::generate(shell, &mut command, bin_name, &mut std::io::stdout())
+                }
+            }
+            Ok(())
+        }
+        args::Command::Models(args) => {
+            for model in ai_query::list_models(
+                args.endpoint.url(),
+                args.endpoint.auth_token(),
+                args.endpoint.auth_scheme.into(),
+                args.endpoint.proxy.as_deref(),
+            )
+            .await?
+            {
+                println!("{}", model);
+            }
             Ok(())
         }
-        args::Command::Ask(args) => {
-            let theme = if args.accessibility_mode {
-                Theme::accessibility()
+        args::Command::Bench(args) => bench::run(args).await,
+        args::Command::Validate(args) => validate::run(args).await,
+        args::Command::Ask(mut args) => {
+            let (theme, syntax) = if args.accessibility_mode {
+                (Theme::accessibility(), SyntaxColors::accessibility())
             } else {
-                Theme::synthwave()
+                (Theme::synthwave(), SyntaxColors::synthwave())
+            };
+            let theme = Theme {
+                syntax: args.syntax_colors.then_some(syntax),
+                score_markers: theme.score_markers || args.score_markers,
+                gauge_gradient: args.gauge_gradient,
+                ..theme
+            };
+
+            for (name, ratio) in theme.low_contrast_colors() {
+                eprintln!(
+                    "Warning: low contrast of {} color against background ({:.2}:1, recommended at least {}:1)",
+                    name,
+                    ratio,
+                    theme::MIN_CONTRAST_RATIO
+                );
+            }
+
+            let question = args.resolve_question()?;
+            let diff = args.diff_content()?;
+            let chunks = args.fragments_jsonl_content()?;
+            let files = match (&diff, &args.fragments_jsonl) {
+                (Some(diff), _) => grepowski::diff::changed_ranges(diff)
+                    .into_iter()
+                    .map(|(path, _)| path.display().to_string())
+                    .collect(),
+                // the session is kept for the input of the chunks
+                (None, Some(fragments_jsonl)) => vec![fragments_jsonl.clone()],
+                (None, None) => args.all_files()?,
             };
+            let mut view = Session::load(&files)
+                .filter(|session| session.question == question)
+                .map(|session| session.view)
+                .unwrap_or_default();
+            if args.granularity == args::Granularity::File {
+                view.grouped = true;
+            }
 
-            let ai = AI::new(
-                args.model,
-                args.url,
-                args.auth_token,
+            let ai_query_config: Box<dyn AiQueryConfig> = match &args.json_schema_file {
+                Some(path) => SchemaAiQueryConfig::from_file(path)?.into(),
+                None => DefaultAiQueryConfig.into(),
+            };
+            let mut ai = AI::new(
+                args.model.clone(),
+                args.endpoint.url(),
+                args.endpoint.auth_token(),
                 args.temperature,
-                DefaultAiQueryConfig,
-                args.question,
-            );
+                ai_query_config,
+                question,
+            )
+            .with_auth_scheme(args.endpoint.auth_scheme.into())
+            .with_fallback_urls(args.endpoint.fallback_urls())
+            .with_proxy(args.endpoint.proxy.as_deref())?
+            .with_stream(args.stream)
+            .with_samples(args.samples)
+            .with_sample_choices(args.sample_choices)
+            .with_sample_temperatures(args.sample_temperatures.clone())
+            .with_reparse_retries(args.reparse_retries)
+            .with_rate_limit(args.rate_limit);
+            if let Some(request_log) = &args.request_log {
+                let file = std::fs::File::create(request_log)
+                    .map_err(|e| anyhow::anyhow!("error creating {}: {}", request_log, e))?;
+                ai = ai.with_request_log(file);
+            }
 
-            let fragments = args
-                .files
-                .iter()
-                .flat_map(|file| -> anyhow::Result<Vec<fragment::Fragment>> {
-                    fragment::file_to_fragments(
-                        file,
-                        args.lines_per_block,
-                        args.blocks_per_fragment,
-                        theme,
-                    )
-                })
-                .flatten()
+            // byte based fragments of a smaller file are the whole file, so --whole-file only
+            // matters without a size limit
+            let fragment_size = match (args.fragment_bytes, args.fragment_tokens) {
+                (Some(bytes), _) => FragmentSize::Bytes(bytes),
+                (None, Some(tokens)) => FragmentSize::tokens(tokens),
+                (None, None) if args.whole_file => FragmentSize::WholeFile,
+                (None, None) => FragmentSize::Lines {
+                    lines_per_block: args.lines_per_block,
+                    blocks_per_fragment: args.blocks_per_fragment,
+                },
+            };
+
+            let fragment_options = FragmentOptions {
+                size: fragment_size,
+                min_lines: args.min_fragment_lines,
+                syntax_overrides: args.syntax_map.clone(),
+                syntax_dir: args.syntax_dir.as_ref().map(PathBuf::from),
+                by_symbol: args.fragment_by == args::FragmentBy::Symbol,
+                max_lines: args.max_lines_per_file,
+                // the tui doesn't run when only printing the prompt
+                no_highlight: args.print_prompt,
+            };
+            // fail early on broken syntaxes instead of skipping every file
+            grepowski::fragment::syntax_set(fragment_options.syntax_dir.as_deref())?;
+
+            let (fragments, skipped) = match (&diff, &chunks) {
+                (Some(diff), _) => {
+                    grepowski::load_diff_fragments(diff, fragment_options.clone(), theme)
+                }
+                (None, Some(chunks)) => {
+                    grepowski::load_jsonl_fragments(chunks, fragment_options.clone(), theme)?
+                }
+                (None, None) => {
+                    grepowski::load_fragments_with_skipped(&files, fragment_options.clone(), theme)
+                }
+            };
+            let user_template = args.user_template.as_deref().map(Arc::from);
+            let (context_lines, path_in_prompt) =
+                (args.context_lines, args.include_path || args.flatten);
+            let prepare = move |fragment: Fragment| {
+                fragment
+                    .with_context_lines(context_lines)
+                    .with_path_in_prompt(path_in_prompt)
+                    .with_prompt_template(user_template.clone())
+            };
+            let fragments = fragments
+                .into_iter()
+                .map(prepare.clone())
                 .collect::<Vec<_>>();
+            if args.flatten {
+                ai = ai.with_context(grepowski::fragment::flatten(&fragments));
+            }
+
+            if args.print_prompt {
+                let template =
+                    fragment::prompt_template(path_in_prompt, args.user_template.as_deref());
+                let codes = vec![template; args.batch_size.max(1)];
+                for (role, content) in ai.messages(&codes) {
+                    println!("--- {} ---\n{}\n", role, content);
+                }
+                return Ok(());
+            }
+
+            if let Some(max_fragments) = args.max_fragments
+                && fragments.len() > max_fragments
+                && !args.yes
+            {
+                anyhow::bail!(
+                    "{} fragments exceed --max-fragments {}, pass --yes to evaluate them anyway",
+                    fragments.len(),
+                    max_fragments
+                );
+            }
+
+            let output = args
+                .output
+                .clone()
+                .map(|path| ResultOutput::create(path, args.sort_output))
+                .transpose()?
+                .map(|output| {
+                    output.with_html((args.export_format == args::ExportFormat::Html).then(|| {
+                        HtmlExport {
+                            question: ai.question().to_string(),
+                            model: args.model.clone(),
+                            theme,
+                        }
+                    }))
+                });
 
             let (tx_tui, rx_tui) = tokio::sync::mpsc::channel(8);
-            let tui = tokio::spawn(tui::Tui::new(fragments.len(), theme).run(rx_tui));
+            let (tx_request, rx_request) = tokio::sync::mpsc::unbounded_channel();
+            let _watcher = if args.watch {
+                let read = move |path: &Path| {
+                    let (fragments, _) = grepowski::load_fragments_with_skipped(
+                        [path],
+                        fragment_options.clone(),
+                        theme,
+                    );
+                    fragments.into_iter().map(prepare.clone()).collect()
+                };
+                Some(watch::watch(&files, tx_request.clone(), read)?)
+            } else {
+                None
+            };
+            let tui = tokio::spawn(
+                tui::Tui::new(
+                    fragments.len(),
+                    theme,
+                    tx_request,
+                    TuiOptions {
+                        sort_by: args.sort.into(),
+                        sort_order: args.sort_order.map_or(
+                            fragment_evaluation::SortBy::from(args.sort).default_order(),
+                            Into::into,
+                        ),
+                        view,
+                        compact: args.compact,
+                        full_history: args.full_history,
+                        list_only: args.list_only,
+                        effect: EffectOptions {
+                            width: args.effect_width,
+                            strength: args.effect_strength,
+                            speed: args.effect_speed,
+                            skip_intro: args.no_intro,
+                        },
+                        title_progress: args.title_progress,
+                        normalize: args.normalize.map(Into::into),
+                    },
+                )
+                .run(rx_tui),
+            );
 
-            let result = input_and_main_flow(fragments, &std::convert::identity(tx_tui), ai).await;
+            let usage = Cell::new(Usage::default());
+            let result = input_and_main_flow(
+                fragments,
+                &std::convert::identity(tx_tui),
+                rx_request,
+                &mut ai,
+                &usage,
+                args.confirm_quit,
+                GatherOptions {
+                    query: grepowski::GatherOptions {
+                        continue_on_error: args.continue_on_error,
+                        batch_size: args.batch_size,
+                        by_file: args.gather_by_file,
+                        interleave_files: args.interleave_files,
+                        normalize: args.normalize.map(Into::into),
+                    },
+                    output,
+                    chart: args
+                        .chart_out
+                        .clone()
+                        .map(|path| ChartExport { path, theme }),
+                },
+            )
+            .await;
 
-            tui.await??;
+            if let Some(view) = tui.await?? {
+                // losing the session only affects convenience, so don't fail the run
+                let _ = Session {
+                    question: ai.question().to_string(),
+                    view,
+                }
+                .save(&files);
+            }
+
+            report_usage(usage.get(), args.price_per_1k);
+            if ai.reparses() > 0 {
+                eprintln!(
+                    "Queried {} unparseable response(s) again with a format reminder",
+                    ai.reparses()
+                );
+            }
+            report_skipped(&skipped, args.verbose);
 
             result
         }
@@ -0,0 +1,95 @@
+use crate::fragment::split_line_range;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Parts of the results view that are restored when re-running on the same inputs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ViewState {
+    pub selected_location: Option<String>,
+    pub grouped: bool,
+    /// Minimum score of the listed fragments, if the slider is shown.
+    #[serde(default)]
+    pub threshold: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub question: String,
+    pub view: ViewState,
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("grepowski"))
+}
+
+/// Location of the session file for a set of input files, see [`session_key`].
+pub fn session_path(files: &[String]) -> Option<PathBuf> {
+    Some(cache_dir()?.join(format!("session-{:016x}.json", session_key(files))))
+}
+
+/// FNV-1a hash of the canonical paths of `files`, independent of their order and stable across
+/// Rust releases, unlike [`std::hash::DefaultHasher`].
+fn session_key(files: &[String]) -> u64 {
+    let mut files: Vec<String> = files.iter().map(|file| canonical_file(file)).collect();
+    files.sort();
+    files.iter().fold(0xcbf2_9ce4_8422_2325, |hash, file| {
+        file.bytes().chain([0]).fold(hash, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    })
+}
+
+/// `file` with its path canonicalized and the line range kept, if the file exists.
+fn canonical_file(file: &str) -> String {
+    let (path, range) = split_line_range(Path::new(file));
+    let path = path.canonicalize().unwrap_or(path);
+    match range {
+        Some((first, last)) => format!("{}:{}-{}", path.display(), first + 1, last + 1),
+        None => path.display().to_string(),
+    }
+}
+
+impl Session {
+    pub fn load(files: &[String]) -> Option<Self> {
+        let content = std::fs::read_to_string(session_path(files)?).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    pub fn save(&self, files: &[String]) -> crate::Result<()> {
+        let path = session_path(files).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "No cache directory found")
+        })?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::session_key;
+
+    #[test]
+    fn session_key_ignores_file_order_and_spelling() {
+        let key = |files: &[&str]| {
+            session_key(
+                &files
+                    .iter()
+                    .map(|file| file.to_string())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let a = key(&["src/lib.rs", "src/main.rs"]);
+        assert_eq!(a, key(&["./src/main.rs", "src/../src/lib.rs"]));
+        assert_ne!(a, key(&["src/lib.rs"]));
+        assert_ne!(key(&["src/lib.rs"]), key(&["src/lib.rs:1-10"]));
+        assert_eq!(key(&["src/lib.rs:1-10"]), key(&["./src/lib.rs:1-10"]));
+        // stable across runs and toolchains
+        assert_eq!(key(&["no/such/file.rs"]), 0xedc2_103f_7636_b5cc);
+    }
+}
@@ -0,0 +1,298 @@
+use ratatui::style::Color;
+use std::str::FromStr;
+pub use syntect::highlighting::{Color as SyntectColor, Theme as SyntectTheme};
+use syntect::highlighting::{ScopeSelectors, StyleModifier, ThemeItem, ThemeSettings};
+use tachyonfx::ToRgbComponents;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Theme {
+    pub title: Color,
+    pub highlight: Color,
+    pub text: Color,
+    pub gauge: Color,
+    /// Color the gauge shifts to from `gauge` as gathering progresses, if `gauge_gradient`.
+    pub gauge_done: Color,
+    pub gauge_gradient: bool,
+    pub border: Color,
+    pub background: Color,
+    /// Values that rose since the previous run in watch mode.
+    pub rise: Color,
+    /// Values that fell since the previous run in watch mode.
+    pub fall: Color,
+    pub fx_enabled: bool,
+    /// Prefix the results with a symbol for the score band, so the ranking doesn't rely on
+    /// color.
+    pub score_markers: bool,
+    /// Distinct colors for groups of syntax scopes, without them code only uses `text` and
+    /// `highlight`.
+    pub syntax: Option<SyntaxColors>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SyntaxColors {
+    pub keyword: Color,
+    pub string: Color,
+    pub type_name: Color,
+    pub comment: Color,
+}
+
+impl SyntaxColors {
+    pub fn synthwave() -> Self {
+        Self {
+            keyword: Color::Rgb(0xf8, 0x61, 0xb4),
+            string: Color::Rgb(0xfe, 0xde, 0x5d),
+            type_name: Color::Rgb(0xff, 0x8b, 0x39),
+            comment: Color::Rgb(0x84, 0x8b, 0xbd),
+        }
+    }
+
+    pub fn accessibility() -> Self {
+        Self {
+            keyword: Color::Rgb(0xcc, 0x79, 0xa7),
+            string: Color::Rgb(0xf0, 0xe4, 0x42),
+            type_name: Color::Rgb(0xe6, 0x9f, 0x00),
+            comment: Color::Rgb(0x99, 0x99, 0x99),
+        }
+    }
+}
+
+impl Theme {
+    pub fn synthwave() -> Self {
+        Self {
+            title: Color::Rgb(0xf8, 0x61, 0xb4),
+            highlight: Color::Rgb(0x00, 0xd3, 0xbb),
+            text: Color::Rgb(0xa1, 0xb1, 0xff),
+            gauge: Color::Rgb(0x50, 0x03, 0x23),
+            gauge_done: Color::Rgb(0x7a, 0x3a, 0x00),
+            gauge_gradient: false,
+            border: Color::Rgb(0x42, 0x2a, 0xd5),
+            background: Color::Rgb(0x09, 0x00, 0x2f),
+            rise: Color::Rgb(0x72, 0xf1, 0xb8),
+            fall: Color::Rgb(0xfe, 0x44, 0x50),
+            fx_enabled: true,
+            score_markers: false,
+            syntax: None,
+        }
+    }
+
+    pub fn accessibility() -> Self {
+        Self {
+            title: Color::Rgb(0xcc, 0x79, 0xa7),
+            highlight: Color::Rgb(0x00, 0x9e, 0x73),
+            text: Color::Rgb(0x56, 0xb4, 0xe9),
+            gauge: Color::Rgb(0xe6, 0x9f, 0x00),
+            gauge_done: Color::Rgb(0x00, 0x9e, 0x73),
+            gauge_gradient: false,
+            border: Color::Rgb(0x42, 0x2a, 0xd5),
+            background: Color::Rgb(0x00, 0x00, 0x00),
+            rise: Color::Rgb(0x00, 0x9e, 0x73),
+            fall: Color::Rgb(0xd5, 0x5e, 0x00),
+            fx_enabled: false,
+            score_markers: true,
+            syntax: None,
+        }
+    }
+}
+
+/// Minimum WCAG contrast ratio for normal text (level AA).
+pub const MIN_CONTRAST_RATIO: f64 = 4.5;
+
+fn relative_luminance(color: Color) -> f64 {
+    let (r, g, b) = color.to_rgb();
+    let channel = |c: u8| {
+        let c = c as f64 / 255.0;
+        if c <= 0.03928 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * channel(r) + 0.7152 * channel(g) + 0.0722 * channel(b)
+}
+
+/// WCAG contrast ratio between two colors, ranging from 1 to 21.
+pub fn contrast_ratio(a: Color, b: Color) -> f64 {
+    let (a, b) = (relative_luminance(a), relative_luminance(b));
+    let (lighter, darker) = if a > b { (a, b) } else { (b, a) };
+    (lighter + 0.05) / (darker + 0.05)
+}
+
+impl Theme {
+    /// Gauge color at `ratio` of the progress, interpolated towards `gauge_done` if
+    /// `gauge_gradient` is set.
+    pub fn gauge_color(&self, ratio: f64) -> Color {
+        if !self.gauge_gradient {
+            return self.gauge;
+        }
+        let ratio = ratio.clamp(0.0, 1.0);
+        let (from, to) = (self.gauge.to_rgb(), self.gauge_done.to_rgb());
+        let mix =
+            |from: u8, to: u8| (from as f64 + (to as f64 - from as f64) * ratio).round() as u8;
+        Color::Rgb(mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+    }
+
+    /// Text colors whose contrast against the background is below [`MIN_CONTRAST_RATIO`],
+    /// together with the actual ratio.
+    pub fn low_contrast_colors(&self) -> Vec<(&'static str, f64)> {
+        let syntax = self.syntax.map(|syntax| {
+            [
+                ("keyword", syntax.keyword),
+                ("string", syntax.string),
+                ("type", syntax.type_name),
+                ("comment", syntax.comment),
+            ]
+        });
+        [
+            ("title", self.title),
+            ("highlight", self.highlight),
+            ("text", self.text),
+            ("rise", self.rise),
+            ("fall", self.fall),
+        ]
+        .into_iter()
+        .chain(syntax.into_iter().flatten())
+        .map(|(name, color)| (name, contrast_ratio(color, self.background)))
+        .filter(|(_, ratio)| *ratio < MIN_CONTRAST_RATIO)
+        .collect()
+    }
+}
+
+fn color_to_syntect(value: Color) -> SyntectColor {
+    let (r, g, b) = value.to_rgb();
+    SyntectColor { r, g, b, a: 0xff }
+}
+
+const HIGHLIGHT_SCOPES: &str = "variable, variable.other, variable.readwrite, entity.name, entity.name.function, entity.name.method, constant, constant.numeric, constant.character";
+const TYPE_SCOPES: &str = "entity.name.type, entity.name.class, entity.other.inherited-class, support.type, support.class, storage.type";
+const STRING_SCOPES: &str = "string, string.quoted, string.other";
+const KEYWORD_SCOPES: &str = "keyword, storage.modifier";
+const COMMENT_SCOPES: &str = "comment";
+
+fn theme_item(scopes: &str, color: Color) -> ThemeItem {
+    ThemeItem {
+        scope: ScopeSelectors::from_str(scopes).expect("Scope selector from string expected"),
+        style: StyleModifier {
+            foreground: Some(color_to_syntect(color)),
+            background: None,
+            font_style: None,
+        },
+    }
+}
+
+impl From<Theme> for SyntectTheme {
+    fn from(value: Theme) -> SyntectTheme {
+        let background_color = color_to_syntect(value.background);
+        let text_color = color_to_syntect(value.text);
+        let scopes = match value.syntax {
+            None => vec![theme_item(
+                &[HIGHLIGHT_SCOPES, TYPE_SCOPES, STRING_SCOPES].join(", "),
+                value.highlight,
+            )],
+            Some(syntax) => vec![
+                theme_item(HIGHLIGHT_SCOPES, value.highlight),
+                theme_item(TYPE_SCOPES, syntax.type_name),
+                theme_item(STRING_SCOPES, syntax.string),
+                theme_item(KEYWORD_SCOPES, syntax.keyword),
+                theme_item(COMMENT_SCOPES, syntax.comment),
+            ],
+        };
+        syntect::highlighting::Theme {
+            name: Some(
+                if value.syntax.is_some() {
+                    "syntax-colors"
+                } else {
+                    "two-color"
+                }
+                .to_string(),
+            ),
+            settings: ThemeSettings {
+                background: Some(background_color),
+                foreground: Some(text_color),
+                caret: None,
+                selection: None,
+                inactive_selection: None,
+                inactive_selection_foreground: None,
+                selection_border: None,
+                selection_foreground: None,
+                line_highlight: None,
+                misspelling: None,
+                gutter: None,
+                gutter_foreground: Some(text_color),
+                guide: None,
+                active_guide: None,
+                stack_guide: None,
+                highlight: None,
+                find_highlight: None,
+                find_highlight_foreground: None,
+                brackets_foreground: None,
+                brackets_background: None,
+                brackets_options: Default::default(),
+                bracket_contents_foreground: None,
+                bracket_contents_options: Default::default(),
+                tags_options: Default::default(),
+                tags_foreground: None,
+                shadow: None,
+                minimap_border: None,
+                accent: None,
+                popup_css: None,
+                phantom_css: None,
+            },
+            scopes,
+            author: Some("auto-generated".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_themes_have_enough_contrast() {
+        assert!(Theme::synthwave().low_contrast_colors().is_empty());
+        assert!(Theme::accessibility().low_contrast_colors().is_empty());
+        for theme in [
+            Theme {
+                syntax: Some(SyntaxColors::synthwave()),
+                ..Theme::synthwave()
+            },
+            Theme {
+                syntax: Some(SyntaxColors::accessibility()),
+                ..Theme::accessibility()
+            },
+        ] {
+            assert!(theme.low_contrast_colors().is_empty());
+        }
+    }
+
+    #[test]
+    fn gauge_color_interpolates_with_gradient() {
+        let theme = Theme {
+            gauge: Color::Rgb(0, 100, 200),
+            gauge_done: Color::Rgb(200, 100, 0),
+            ..Theme::synthwave()
+        };
+        assert_eq!(theme.gauge_color(0.5), Color::Rgb(0, 100, 200));
+        let theme = Theme {
+            gauge_gradient: true,
+            ..theme
+        };
+        assert_eq!(theme.gauge_color(0.0), Color::Rgb(0, 100, 200));
+        assert_eq!(theme.gauge_color(0.5), Color::Rgb(100, 100, 100));
+        assert_eq!(theme.gauge_color(2.0), Color::Rgb(200, 100, 0));
+    }
+
+    #[test]
+    fn low_contrast_is_reported() {
+        let theme = Theme {
+            text: Color::Rgb(0x10, 0x10, 0x10),
+            ..Theme::accessibility()
+        };
+        let low = theme.low_contrast_colors();
+        assert_eq!(low.len(), 1);
+        assert_eq!(low[0].0, "text");
+        assert!(
+            (contrast_ratio(Color::Rgb(0, 0, 0), Color::Rgb(0xff, 0xff, 0xff)) - 21.0).abs() < 1e-9
+        );
+    }
+}
@@ -19,7 +19,7 @@ impl FxFilter {
             main_rects
                 .iter()
                 .cloned()
-                .map(|r| tachyonfx::CellFilter::RefArea(r))
+                .map(tachyonfx::CellFilter::RefArea)
                 .collect(),
         );
 
@@ -46,9 +46,10 @@ impl FxFilter {
     }
 
     pub fn assign(&mut self, rect: Rect) -> anyhow::Result<()> {
-        self.main_rects.get_mut(self.assign_idx).ok_or(
-            anyhow::anyhow!("No more rects available"),
-        )?.set(rect);
+        self.main_rects
+            .get_mut(self.assign_idx)
+            .ok_or(anyhow::anyhow!("No more rects available"))?
+            .set(rect);
         self.assign_idx += 1;
         Ok(())
     }
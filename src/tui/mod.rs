@@ -1,7 +1,6 @@
 mod fx_filter;
-mod theme;
+#[allow(clippy::module_inception)]
 mod tui;
 
 pub use fx_filter::*;
-pub use theme::*;
 pub use tui::*;
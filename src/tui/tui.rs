@@ -1,17 +1,31 @@
-use crate::tui::{FxFilter, Theme};
-use crate::{fragment::Fragment, fragment_evaluation::FragmentEvaluation};
+use crate::tui::FxFilter;
+use crossterm::terminal::SetTitle;
+use grepowski::{
+    ai_query::QueryResult,
+    fragment::Fragment,
+    fragment_evaluation::{self, Change, FragmentEvaluation, Normalize, SortBy, SortOrder},
+    session::ViewState,
+    theme::Theme,
+};
 use ratatui::{
     layout::{Constraint, Direction, Margin},
     style::Styled,
-    symbols::Marker,
+    symbols::{Marker, bar},
+    text::Line,
     widgets::{
-        Axis, Block, BorderType, Chart, Dataset, Gauge, ListItem, ListState, Paragraph, Wrap,
+        Axis, Block, BorderType, Chart, Clear, Dataset, Gauge, GraphType, ListItem, ListState,
+        Paragraph, Wrap,
     },
     {DefaultTerminal, Frame, style::Stylize},
 };
-use std::{collections::VecDeque, time::Instant};
+use std::{
+    collections::{HashSet, VecDeque},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 use tachyonfx::{EffectRenderer, color_from_hsl, color_to_hsl};
-use tokio::{select, time::MissedTickBehavior};
+use tokio::{select, sync::mpsc::UnboundedSender, time::MissedTickBehavior};
 
 const EFFECT_WIDTH: f32 = 20.0;
 const EFFECT_STRENGTH: f32 = 50.0;
@@ -20,43 +34,861 @@ const EFFECT_DELAY_MILLIS: u32 = 7500;
 const INITIAL_EFFECT_MILLIS: u32 = 500;
 const INITIAL_EFFECT_DELAY_MILLIS: u32 = 4000;
 
+/// Below this terminal height the gather view only shows the current location and progress.
+const COMPACT_GATHER_HEIGHT: u16 = 15;
+const HORIZONTAL_SCROLL_STEP: u16 = 4;
+const THRESHOLD_STEP: f32 = 0.05;
+/// Maximum width of the score sparkline of a file in the grouped view.
+const SPARKLINE_WIDTH: usize = 16;
+const SUMMARY_REASONS: usize = 3;
+
 const EXTRA_RENDER_INTERVAL: std::time::Duration = std::time::Duration::from_millis(15);
 
+/// Time to confirm quitting while gathering with a second key press.
+pub const QUIT_CONFIRM_WINDOW: std::time::Duration = std::time::Duration::from_secs(2);
+const QUIT_PROMPT: &str = " Press q or y again to quit ";
+
+/// Lower bounds of the high and medium score bands shown with `Theme::score_markers`.
+const HIGH_SCORE: f32 = 2.0 / 3.0;
+const MEDIUM_SCORE: f32 = 1.0 / 3.0;
+
+/// English suffix of the ordinal of `n`, e.g. `rd` for 23.
+fn ordinal_suffix(n: usize) -> &'static str {
+    match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    }
+}
+
+/// Bars of `values` like ratatui's `Sparkline`, at most `width` long. With more values, each
+/// bar shows the highest value of its share, so single matches stay visible.
+fn sparkline(values: &[f32], width: usize) -> String {
+    let levels = [
+        bar::NINE_LEVELS.one_eighth,
+        bar::NINE_LEVELS.one_quarter,
+        bar::NINE_LEVELS.three_eighths,
+        bar::NINE_LEVELS.half,
+        bar::NINE_LEVELS.five_eighths,
+        bar::NINE_LEVELS.three_quarters,
+        bar::NINE_LEVELS.seven_eighths,
+        bar::NINE_LEVELS.full,
+    ];
+    let bars = values.len().min(width);
+    (0..bars)
+        .map(|bar| {
+            let (start, end) = (bar * values.len() / bars, (bar + 1) * values.len() / bars);
+            let value = values[start..end].iter().copied().fold(0.0, f32::max);
+            levels[(value.clamp(0.0, 1.0) * 7.0).round() as usize]
+        })
+        .collect()
+}
+
+/// Symbol of the score band of `value`.
+fn score_marker(value: f32) -> &'static str {
+    if value >= HIGH_SCORE {
+        "▲"
+    } else if value >= MEDIUM_SCORE {
+        "■"
+    } else {
+        "▼"
+    }
+}
+
 #[derive(Debug, Clone)]
 struct GatherDataState {
+    compact: bool,
+    full_history: bool,
+    /// Hide the chart and the progress panel, leaving a single progress line below the code.
+    code_only: bool,
     value_history: VecDeque<f32>,
     current_fragment: Option<Fragment>,
+    partial_response: String,
+    quit_prompt_until: Option<Instant>,
+    failures: usize,
+    last_failure: Option<String>,
     count: usize,
     count_max: usize,
 }
 
 impl GatherDataState {
-    fn new(count_max: usize) -> Self {
+    fn new(count_max: usize, compact: bool, full_history: bool) -> Self {
         Self {
+            compact,
+            full_history,
+            code_only: false,
             value_history: VecDeque::new(),
             current_fragment: None,
+            partial_response: String::new(),
+            quit_prompt_until: None,
+            failures: 0,
+            last_failure: None,
             count: 0,
             count_max,
         }
     }
+
+    /// Whole percentage of fragments done, for the terminal title.
+    fn percent(&self) -> u8 {
+        (self.ratio() * 100.0) as u8
+    }
+
+    /// Count of fragments done, capped at `count_max` in case events arrive out of order.
+    fn shown_count(&self) -> usize {
+        self.count.min(self.count_max)
+    }
+
+    fn ratio(&self) -> f64 {
+        if self.count_max == 0 {
+            0.0
+        } else {
+            (self.shown_count() as f64 / self.count_max as f64).clamp(0.0, 1.0)
+        }
+    }
+
+    fn progress_label(&self) -> String {
+        if self.failures == 0 {
+            format!("{}/{}", self.shown_count(), self.count_max)
+        } else {
+            format!(
+                "{}/{}, {} failed",
+                self.shown_count(),
+                self.count_max,
+                self.failures
+            )
+        }
+    }
+
+    fn quit_prompt(&self) -> Option<&'static str> {
+        self.quit_prompt_until
+            .filter(|until| Instant::now() < *until)
+            .map(|_| QUIT_PROMPT)
+    }
+
+    fn mean(&self) -> Option<f32> {
+        if self.value_history.is_empty() {
+            return None;
+        }
+        Some(self.value_history.iter().sum::<f32>() / self.value_history.len() as f32)
+    }
+
+    /// Values to plot in a chart with room for `points` values, either the latest ones or the
+    /// whole history averaged into `points` buckets.
+    fn chart_values(&self, points: usize) -> Vec<f32> {
+        let len = self.value_history.len();
+        if len <= points {
+            return self.value_history.iter().copied().collect();
+        }
+        if !self.full_history {
+            return self.value_history.range(len - points..).copied().collect();
+        }
+        (0..points)
+            .map(|bucket| {
+                let start = bucket * len / points;
+                let end = (bucket + 1) * len / points;
+                self.value_history.range(start..end).sum::<f32>() / (end - start) as f32
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DisplayRow {
+    File(usize),
+    Fragment(usize),
+}
+
+#[derive(Debug, Clone)]
+struct FileGroup {
+    path: PathBuf,
+    eval_indices: Vec<usize>,
+    expanded: bool,
+}
+
+#[derive(Debug, Clone)]
+struct SearchState {
+    query: String,
+    origin_idx: usize,
+}
+
+/// Answer of the model about why the question applies to a fragment, shown in a popup.
+#[derive(Debug, Clone)]
+struct Explanation {
+    location: String,
+    /// The explanation or the error message, `None` while waiting for the model.
+    answer: Option<Result<String, String>>,
 }
 
 #[derive(Debug, Clone)]
 struct DisplayDataState {
+    /// A new question being typed, if any.
+    question: Option<String>,
     eval: Vec<FragmentEvaluation>,
+    groups: Vec<FileGroup>,
+    grouped: bool,
     current_idx: usize,
     list_state: ListState,
+    page_size: usize,
+    search: Option<SearchState>,
+    requerying: HashSet<String>,
+    requeried: HashSet<String>,
+    requery_failed: bool,
+    sort_by: SortBy,
+    sort_order: SortOrder,
+    wrap: bool,
+    /// Horizontal scroll offset of the code pane, reset whenever the selection changes.
+    scroll_x: u16,
+    show_summary: bool,
+    show_raw: bool,
+    /// Show the code without syntax highlighting.
+    plain: bool,
+    list_only: bool,
+    normalize: Option<Normalize>,
+    /// Fragments of changed files that are gone since the previous run in watch mode.
+    removed: usize,
+    /// Fragments hidden from the results, the most recently hidden last.
+    hidden: Vec<FragmentEvaluation>,
+    /// Minimum score of the listed fragments, moved with a slider while set.
+    threshold: Option<f32>,
+    explanation: Option<Explanation>,
 }
 
 impl DisplayDataState {
-    fn new(eval: Vec<FragmentEvaluation>) -> Self {
+    fn new(mut eval: Vec<FragmentEvaluation>, sort_by: SortBy, sort_order: SortOrder) -> Self {
+        fragment_evaluation::sort(&mut eval, sort_by, sort_order);
+        let groups = Self::make_groups(&eval);
         let current_idx = 0;
         let list_state = ListState::default();
         Self {
+            question: None,
             eval,
+            groups,
+            grouped: false,
             current_idx,
             list_state,
+            page_size: 1,
+            search: None,
+            requerying: HashSet::new(),
+            requeried: HashSet::new(),
+            requery_failed: false,
+            sort_by,
+            sort_order,
+            wrap: true,
+            scroll_x: 0,
+            show_summary: false,
+            show_raw: false,
+            plain: false,
+            list_only: false,
+            normalize: None,
+            removed: 0,
+            hidden: Vec::new(),
+            threshold: None,
+            explanation: None,
+        }
+    }
+
+    fn make_groups(eval: &[FragmentEvaluation]) -> Vec<FileGroup> {
+        let mut groups: Vec<FileGroup> = Vec::new();
+        for (idx, e) in eval.iter().enumerate() {
+            match groups.iter_mut().find(|g| g.path == e.fragment.path()) {
+                Some(group) => group.eval_indices.push(idx),
+                None => groups.push(FileGroup {
+                    path: e.fragment.path().to_path_buf(),
+                    eval_indices: vec![idx],
+                    expanded: false,
+                }),
+            }
+        }
+        groups
+    }
+
+    fn rows(&self) -> Vec<DisplayRow> {
+        if !self.grouped {
+            return (0..self.eval.len())
+                .filter(|&idx| self.shown(idx))
+                .map(DisplayRow::Fragment)
+                .collect();
+        }
+        let mut rows = Vec::new();
+        for (group_idx, group) in self.groups.iter().enumerate() {
+            if self.shown_count(group_idx) == 0 {
+                continue;
+            }
+            rows.push(DisplayRow::File(group_idx));
+            if group.expanded {
+                rows.extend(
+                    group
+                        .eval_indices
+                        .iter()
+                        .copied()
+                        .filter(|&idx| self.shown(idx))
+                        .map(DisplayRow::Fragment),
+                );
+            }
+        }
+        rows
+    }
+
+    /// Whether the fragment reaches the threshold, if one is set.
+    fn shown(&self, eval_idx: usize) -> bool {
+        self.threshold
+            .is_none_or(|threshold| self.eval[eval_idx].value >= threshold)
+    }
+
+    /// Values of the listed fragments of the file in line order.
+    fn line_ordered_values(&self, group_idx: usize) -> Vec<f32> {
+        let mut indices: Vec<usize> = self.groups[group_idx]
+            .eval_indices
+            .iter()
+            .copied()
+            .filter(|&idx| self.shown(idx))
+            .collect();
+        indices.sort_by_key(|&idx| self.eval[idx].fragment.first_line());
+        indices.iter().map(|&idx| self.eval[idx].value).collect()
+    }
+
+    fn shown_count(&self, group_idx: usize) -> usize {
+        self.groups[group_idx]
+            .eval_indices
+            .iter()
+            .filter(|&&idx| self.shown(idx))
+            .count()
+    }
+
+    fn row_label(&self, row: DisplayRow) -> String {
+        match row {
+            DisplayRow::File(group_idx) => {
+                let group = &self.groups[group_idx];
+                let best_value = self
+                    .best_eval_idx(group_idx)
+                    .map_or(0.0, |idx| self.eval[idx].value);
+                format!(
+                    "{} {} {:.3} ({}) {}",
+                    if group.expanded { "▾" } else { "▸" },
+                    group.path.display(),
+                    best_value,
+                    self.shown_count(group_idx),
+                    sparkline(&self.line_ordered_values(group_idx), SPARKLINE_WIDTH)
+                )
+            }
+            DisplayRow::Fragment(eval_idx) => {
+                let e = &self.eval[eval_idx];
+                let indent = if self.grouped { "  " } else { "" };
+                let location = e.fragment.location();
+                let marker = if self.requerying.contains(&location) {
+                    " …"
+                } else if self.requeried.contains(&location) {
+                    " ↻"
+                } else {
+                    ""
+                };
+                format!("{}{} {}{}", indent, location, e.value_label(), marker)
+            }
+        }
+    }
+
+    fn row_value(&self, row: DisplayRow) -> f32 {
+        match row {
+            DisplayRow::File(group_idx) => self
+                .best_eval_idx(group_idx)
+                .map_or(0.0, |idx| self.eval[idx].value),
+            DisplayRow::Fragment(eval_idx) => self.eval[eval_idx].value,
+        }
+    }
+
+    /// Change since the previous run in watch mode, shown after the label.
+    fn row_change(&self, row: DisplayRow) -> Option<Change> {
+        match row {
+            DisplayRow::File(_) => None,
+            DisplayRow::Fragment(eval_idx) => self.eval[eval_idx].change,
+        }
+    }
+
+    fn row_location(&self, row: DisplayRow) -> String {
+        match row {
+            DisplayRow::File(group_idx) => self.groups[group_idx].path.display().to_string(),
+            DisplayRow::Fragment(eval_idx) => self.eval[eval_idx].fragment.location(),
+        }
+    }
+
+    fn row_path(&self, row: DisplayRow) -> &Path {
+        match row {
+            DisplayRow::File(group_idx) => &self.groups[group_idx].path,
+            DisplayRow::Fragment(eval_idx) => self.eval[eval_idx].fragment.path(),
+        }
+    }
+
+    /// Row index of the first row of the next file, or of the current file's first row when
+    /// going back, and of the previous file's when already there.
+    fn file_boundary(&self, forward: bool) -> usize {
+        let paths: Vec<&Path> = self
+            .rows()
+            .into_iter()
+            .map(|row| self.row_path(row))
+            .collect();
+        let Some(&current) = paths.get(self.current_idx) else {
+            return self.current_idx;
+        };
+        let run_start = |mut idx: usize| {
+            while idx > 0 && paths[idx - 1] == paths[idx] {
+                idx -= 1;
+            }
+            idx
+        };
+        if forward {
+            paths[self.current_idx..]
+                .iter()
+                .position(|&path| path != current)
+                .map_or(self.current_idx, |offset| self.current_idx + offset)
+        } else {
+            match run_start(self.current_idx) {
+                start if start == self.current_idx && start > 0 => run_start(start - 1),
+                start => start,
+            }
+        }
+    }
+
+    fn selected_eval_idx(&self) -> Option<usize> {
+        match self.rows().get(self.current_idx)? {
+            DisplayRow::File(group_idx) => self.best_eval_idx(*group_idx),
+            DisplayRow::Fragment(eval_idx) => Some(*eval_idx),
+        }
+    }
+
+    fn best_eval_idx(&self, group_idx: usize) -> Option<usize> {
+        self.groups[group_idx]
+            .eval_indices
+            .iter()
+            .copied()
+            .max_by(|&a, &b| {
+                self.eval[a]
+                    .value
+                    .partial_cmp(&self.eval[b].value)
+                    .expect("Order expected")
+            })
+    }
+
+    fn group_of(&self, eval_idx: usize) -> Option<usize> {
+        self.groups
+            .iter()
+            .position(|g| g.eval_indices.contains(&eval_idx))
+    }
+
+    fn select_row(&mut self, row: DisplayRow) {
+        if let Some(idx) = self.rows().iter().position(|r| *r == row) {
+            self.select(idx);
+        }
+    }
+
+    /// Selects the row of the fragment, or of its file group if that is collapsed.
+    fn select_eval(&mut self, eval_idx: usize) {
+        let rows = self.rows();
+        if rows.contains(&DisplayRow::Fragment(eval_idx)) {
+            self.select_row(DisplayRow::Fragment(eval_idx));
+        } else if let Some(group_idx) = self.group_of(eval_idx) {
+            self.select_row(DisplayRow::File(group_idx));
+        }
+    }
+
+    fn extreme_eval_idx(&self, best: bool) -> Option<usize> {
+        let by_value = |a: &usize, b: &usize| {
+            self.eval[*a]
+                .value
+                .partial_cmp(&self.eval[*b].value)
+                .expect("Order expected")
+        };
+        let indices = (0..self.eval.len()).filter(|&idx| self.shown(idx));
+        if best {
+            indices.max_by(by_value)
+        } else {
+            indices.min_by(by_value)
+        }
+    }
+
+    fn toggle_grouped(&mut self) {
+        let selected = self.selected_eval_idx();
+        self.grouped = !self.grouped;
+        self.current_idx = 0;
+        let Some(eval_idx) = selected else {
+            return;
+        };
+        if !self.grouped {
+            self.select_row(DisplayRow::Fragment(eval_idx));
+        } else if let Some(group_idx) = self.group_of(eval_idx) {
+            if self.groups[group_idx].expanded {
+                self.select_row(DisplayRow::Fragment(eval_idx));
+            } else {
+                self.select_row(DisplayRow::File(group_idx));
+            }
+        }
+    }
+
+    fn toggle_expanded(&mut self) {
+        if !self.grouped {
+            return;
+        }
+        let group_idx = match self.rows().get(self.current_idx) {
+            Some(DisplayRow::File(group_idx)) => *group_idx,
+            Some(DisplayRow::Fragment(eval_idx)) => match self.group_of(*eval_idx) {
+                Some(group_idx) => group_idx,
+                None => return,
+            },
+            None => return,
+        };
+        let group = &mut self.groups[group_idx];
+        group.expanded = !group.expanded;
+        self.select_row(DisplayRow::File(group_idx));
+    }
+
+    /// Selects the row at `idx`, or the last one if there are fewer rows.
+    fn select(&mut self, idx: usize) {
+        let idx = idx.min(self.rows().len().saturating_sub(1));
+        if idx != self.current_idx {
+            self.scroll_x = 0;
+        }
+        self.current_idx = idx;
+    }
+
+    fn clamp_selection(&mut self) {
+        self.select(self.current_idx);
+    }
+
+    fn nav(&mut self, nav: Nav) {
+        match nav {
+            Nav::Up => self.select(self.current_idx.saturating_sub(1)),
+            Nav::Down => self.select(self.current_idx.saturating_add(1)),
+            Nav::PageUp => self.select(self.current_idx.saturating_sub(self.page_size)),
+            Nav::PageDown => self.select(self.current_idx.saturating_add(self.page_size)),
+            Nav::Home => self.select(0),
+            Nav::End => self.select(usize::MAX),
+            Nav::PrevFile => self.select(self.file_boundary(false)),
+            Nav::NextFile => self.select(self.file_boundary(true)),
+            Nav::Best | Nav::Worst => {
+                if let Some(eval_idx) = self.extreme_eval_idx(matches!(nav, Nav::Best)) {
+                    self.select_eval(eval_idx);
+                }
+            }
+            Nav::Left | Nav::Right if let Some(threshold) = self.threshold => {
+                let step = if matches!(nav, Nav::Left) {
+                    -THRESHOLD_STEP
+                } else {
+                    THRESHOLD_STEP
+                };
+                // rounded, so repeated steps land on the same values both ways
+                let threshold = ((threshold + step) * 100.0).round() / 100.0;
+                self.set_threshold(Some(threshold.clamp(0.0, 1.0)));
+            }
+            Nav::Left => self.scroll_x = self.scroll_x.saturating_sub(HORIZONTAL_SCROLL_STEP),
+            Nav::Right => {
+                self.scroll_x = self
+                    .scroll_x
+                    .saturating_add(HORIZONTAL_SCROLL_STEP)
+                    .min(self.max_scroll_x());
+            }
+        }
+        self.clamp_selection();
+    }
+
+    fn max_scroll_x(&self) -> u16 {
+        self.selected_eval_idx()
+            .map(|idx| {
+                self.eval[idx]
+                    .fragment
+                    .highlighted_content()
+                    .iter()
+                    .map(|line| line.width())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .unwrap_or(0)
+            .saturating_sub(1)
+            .try_into()
+            .unwrap_or(u16::MAX)
+    }
+
+    fn set_sort(&mut self, sort_by: SortBy, sort_order: SortOrder) {
+        let selected_location = self
+            .selected_eval_idx()
+            .map(|idx| self.eval[idx].fragment.location());
+        let file_selected = matches!(self.rows().get(self.current_idx), Some(DisplayRow::File(_)));
+
+        self.sort_by = sort_by;
+        self.sort_order = sort_order;
+        fragment_evaluation::sort(&mut self.eval, sort_by, sort_order);
+        self.regroup();
+
+        let Some(eval_idx) = selected_location.and_then(|location| {
+            self.eval
+                .iter()
+                .position(|e| e.fragment.location() == location)
+        }) else {
+            return;
+        };
+        match self.group_of(eval_idx) {
+            Some(group_idx)
+                if self.grouped && (file_selected || !self.groups[group_idx].expanded) =>
+            {
+                self.select_row(DisplayRow::File(group_idx))
+            }
+            _ => self.select_row(DisplayRow::Fragment(eval_idx)),
+        }
+    }
+
+    /// Rebuilds the file groups after `eval` changed, keeping expanded groups expanded.
+    fn regroup(&mut self) {
+        let expanded: HashSet<PathBuf> = self
+            .groups
+            .iter()
+            .filter(|g| g.expanded)
+            .map(|g| g.path.clone())
+            .collect();
+        self.groups = Self::make_groups(&self.eval);
+        for group in &mut self.groups {
+            group.expanded = expanded.contains(&group.path);
+        }
+    }
+
+    /// Lists only fragments scoring at least `threshold`, keeping the selection if it is still
+    /// listed.
+    fn set_threshold(&mut self, threshold: Option<f32>) {
+        let selected = self.selected_eval_idx();
+        self.threshold = threshold;
+        match selected {
+            Some(eval_idx) if self.shown(eval_idx) => self.select_eval(eval_idx),
+            _ => self.clamp_selection(),
+        }
+    }
+
+    fn toggle_threshold(&mut self) {
+        self.set_threshold(match self.threshold {
+            Some(_) => None,
+            None => Some(0.0),
+        });
+    }
+
+    /// Hides the selected fragment from the results, but never the last one. File rows of the
+    /// grouped view are left alone, they don't show which fragment would be hidden.
+    fn hide_selected(&mut self) {
+        if self.eval.len() <= 1 {
+            return;
+        }
+        let Some(&DisplayRow::Fragment(eval_idx)) = self.rows().get(self.current_idx) else {
+            return;
+        };
+        self.hidden.push(self.eval.remove(eval_idx));
+        self.regroup();
+        self.clamp_selection();
+    }
+
+    /// Shows the most recently hidden fragment again and selects it.
+    fn unhide(&mut self) {
+        let Some(eval) = self.hidden.pop() else {
+            return;
+        };
+        let location = eval.fragment.location();
+        self.eval.push(eval);
+        fragment_evaluation::sort(&mut self.eval, self.sort_by, self.sort_order);
+        self.regroup();
+        if let Some(eval_idx) = self
+            .eval
+            .iter()
+            .position(|e| e.fragment.location() == location)
+        {
+            self.select_eval(eval_idx);
+        }
+    }
+
+    fn view_state(&self) -> ViewState {
+        ViewState {
+            selected_location: self
+                .selected_eval_idx()
+                .map(|idx| self.eval[idx].fragment.location()),
+            grouped: self.grouped,
+            threshold: self.threshold,
+        }
+    }
+
+    fn apply_view(&mut self, view: &ViewState) {
+        if view.grouped != self.grouped {
+            self.toggle_grouped();
+        }
+        self.set_threshold(view.threshold);
+        let Some(eval_idx) = view.selected_location.as_ref().and_then(|location| {
+            self.eval
+                .iter()
+                .position(|e| &e.fragment.location() == location)
+        }) else {
+            return;
+        };
+        match self.group_of(eval_idx) {
+            Some(group_idx) if self.grouped => self.select_row(DisplayRow::File(group_idx)),
+            _ => self.select_row(DisplayRow::Fragment(eval_idx)),
+        }
+    }
+
+    /// Reasons of the highest scoring fragments, independent of the current sorting.
+    fn summary(&self) -> Vec<Line<'static>> {
+        let mut top: Vec<_> = self.eval.iter().filter(|e| e.reason.is_some()).collect();
+        top.sort_by(|a, b| b.value.partial_cmp(&a.value).expect("Order expected"));
+        top.into_iter()
+            .take(SUMMARY_REASONS)
+            .flat_map(|e| {
+                [
+                    Line::from(format!("{} {}", e.fragment.location(), e.value_label())).bold(),
+                    Line::from(e.reason.clone().unwrap_or_default()),
+                ]
+            })
+            .collect()
+    }
+
+    fn start_requery(&mut self) -> Option<Fragment> {
+        let fragment = self.eval.get(self.selected_eval_idx()?)?.fragment.clone();
+        self.requery_failed = false;
+        self.requerying
+            .insert(fragment.location())
+            .then_some(fragment)
+    }
+
+    fn finish_requery(&mut self, location: &str, result: Result<QueryResult, String>) {
+        self.requerying.remove(location);
+        let Ok(result) = result else {
+            self.requery_failed = true;
+            return;
+        };
+        let Some(eval_idx) = self
+            .eval
+            .iter()
+            .position(|e| e.fragment.location() == location)
+        else {
+            return;
+        };
+        self.eval[eval_idx].update(result);
+        if let Some(method) = self.normalize {
+            fragment_evaluation::normalize(&mut self.eval, method);
+        }
+        self.requeried.insert(location.to_string());
+    }
+
+    /// Opens the explanation popup for the selected fragment and returns the fragment to ask the
+    /// model about, or closes the popup if it is open.
+    fn start_explain(&mut self) -> Option<Fragment> {
+        if self.explanation.take().is_some() {
+            return None;
+        }
+        let fragment = self.eval.get(self.selected_eval_idx()?)?.fragment.clone();
+        self.explanation = Some(Explanation {
+            location: fragment.location(),
+            answer: None,
+        });
+        Some(fragment)
+    }
+
+    /// Shows the answer, unless the popup was closed or opened for another fragment meanwhile.
+    fn finish_explain(&mut self, location: &str, result: Result<String, String>) {
+        if let Some(explanation) = &mut self.explanation
+            && explanation.location == location
+        {
+            explanation.answer = Some(result);
+        }
+    }
+
+    /// Position of the selected row in the list and the share of the listed fragments scoring
+    /// lower than the selected one, e.g. `3/120, 97th percentile`.
+    fn position(&self) -> Option<String> {
+        let rows = self.rows();
+        let eval_idx = self.selected_eval_idx()?;
+        let value = self.eval[eval_idx].value;
+        let shown: Vec<f32> = (0..self.eval.len())
+            .filter(|&idx| self.shown(idx))
+            .map(|idx| self.eval[idx].value)
+            .collect();
+        let below = shown.iter().filter(|&&v| v < value).count();
+        let percentile = below * 100 / shown.len().max(1);
+        Some(format!(
+            "{}/{}, {}{} percentile",
+            self.current_idx + 1,
+            rows.len(),
+            percentile,
+            ordinal_suffix(percentile)
+        ))
+    }
+
+    fn status(&self) -> Option<String> {
+        if !self.requerying.is_empty() {
+            Some(format!(" requerying… ({}) ", self.requerying.len()))
+        } else if self.requery_failed {
+            Some(" requery failed ".to_string())
+        } else if self.removed > 0 {
+            Some(format!(" {} removed ", self.removed))
+        } else if !self.hidden.is_empty() {
+            Some(format!(" {} hidden ", self.hidden.len()))
+        } else {
+            None
+        }
+    }
+
+    fn search(&mut self, search: Search) {
+        match search {
+            Search::Start => {
+                self.search = Some(SearchState {
+                    query: String::new(),
+                    origin_idx: self.current_idx,
+                });
+            }
+            Search::Input(c) => {
+                if let Some(search) = &mut self.search {
+                    search.query.push(c);
+                }
+                self.search_jump();
+            }
+            Search::Backspace => {
+                if let Some(search) = &mut self.search {
+                    search.query.pop();
+                }
+                self.search_jump();
+            }
+            Search::Confirm => {
+                self.search = None;
+            }
+            Search::Cancel => {
+                if let Some(search) = self.search.take() {
+                    self.select(search.origin_idx);
+                }
+            }
+        }
+    }
+
+    /// Edits the new question, returns it once confirmed.
+    fn question(&mut self, input: Search) -> Option<String> {
+        match input {
+            Search::Start => self.question = Some(String::new()),
+            Search::Input(c) => self.question.iter_mut().for_each(|q| q.push(c)),
+            Search::Backspace => self.question.iter_mut().for_each(|q| {
+                q.pop();
+            }),
+            Search::Confirm => {
+                return self
+                    .question
+                    .take()
+                    .map(|q| q.trim().to_string())
+                    .filter(|q| !q.is_empty());
+            }
+            Search::Cancel => self.question = None,
         }
+        None
+    }
+
+    fn search_jump(&mut self) {
+        let Some(search) = &self.search else {
+            return;
+        };
+        let rows = self.rows();
+        let found = (0..rows.len())
+            .map(|offset| (search.origin_idx + offset) % rows.len())
+            .find(|&idx| self.row_location(rows[idx]).contains(&search.query));
+        self.select(found.unwrap_or(search.origin_idx));
     }
 }
 
@@ -66,32 +898,61 @@ enum TuiDeepState {
     DisplayData(DisplayDataState),
 }
 
+/// Wall clock time for the effect, which only counts whole milliseconds. The remainder is carried
+/// over to the next tick, so frequent renders don't lose time and slow down the animation.
+#[derive(Debug, Default)]
+struct EffectClock {
+    last_instant: Option<Instant>,
+}
+
+impl EffectClock {
+    fn tick(&mut self, now: Instant) -> tachyonfx::Duration {
+        let Some(last_instant) = self.last_instant else {
+            self.last_instant = Some(now);
+            return tachyonfx::Duration::ZERO;
+        };
+        let millis = now.saturating_duration_since(last_instant).as_millis() as u32;
+        self.last_instant = Some(last_instant + std::time::Duration::from_millis(millis.into()));
+        tachyonfx::Duration::from_millis(millis)
+    }
+}
+
 #[derive(Debug)]
 struct TuiState {
     state: TuiDeepState,
-    last_instant: Option<Instant>,
+    effect_clock: EffectClock,
     effect: tachyonfx::Effect,
     fx_filter: FxFilter,
 }
 
 impl TuiState {
-    fn new(count_max: usize) -> Self {
-        let state = TuiDeepState::GatherData(GatherDataState::new(count_max));
+    fn new(count_max: usize, compact: bool, full_history: bool, effect: EffectOptions) -> Self {
+        let state =
+            TuiDeepState::GatherData(GatherDataState::new(count_max, compact, full_history));
 
-        let last_instant = None;
+        let EffectOptions {
+            width,
+            strength,
+            speed,
+            skip_intro,
+        } = effect;
+        let scaled = |millis: u32| (millis as f32 / speed) as u32;
 
         let effect = tachyonfx::fx::effect_fn(
             (),
-            tachyonfx::EffectTimer::from_ms(EFFECT_MILLIS, tachyonfx::Interpolation::Linear),
-            |_, context, cells| {
+            tachyonfx::EffectTimer::from_ms(
+                scaled(EFFECT_MILLIS),
+                tachyonfx::Interpolation::Linear,
+            ),
+            move |_, context, cells| {
                 let area = context.area;
                 let diag_area_dim = (area.width + area.height) as f32;
-                let diag_range_min = -EFFECT_WIDTH;
-                let diag_range_max = diag_area_dim + EFFECT_WIDTH;
+                let diag_range_min = -width;
+                let diag_range_max = diag_area_dim + width;
                 let total_diag_range = diag_range_max - diag_range_min;
                 let progress = context.alpha();
 
-                let effect_width_rel = EFFECT_WIDTH / total_diag_range;
+                let effect_width_rel = width / total_diag_range;
 
                 for (position, cell) in cells {
                     let x_rel = position.x - area.x;
@@ -104,7 +965,7 @@ impl TuiState {
 
                     if diff < effect_width_rel {
                         let (h, s, mut l) = color_to_hsl(&cell.fg);
-                        l += EFFECT_STRENGTH * (effect_width_rel - diff) / effect_width_rel;
+                        l += strength * (effect_width_rel - diff) / effect_width_rel;
                         cell.fg = color_from_hsl(h, s, l);
                     }
                 }
@@ -112,16 +973,25 @@ impl TuiState {
         )
         .reversed();
 
-        let fx_filter = FxFilter::new(3);
+        let fx_filter = FxFilter::new(4);
 
         let effect = effect.with_filter(fx_filter.border_filter());
 
-        let sleep = tachyonfx::fx::sleep(EFFECT_DELAY_MILLIS);
+        let sleep = tachyonfx::fx::sleep(scaled(EFFECT_DELAY_MILLIS));
         let effect = tachyonfx::fx::sequence(&[effect, sleep]);
         let effect = tachyonfx::fx::repeating(effect);
 
-        let initial_effect = tachyonfx::fx::coalesce(INITIAL_EFFECT_MILLIS);
-        let sleep = tachyonfx::fx::sleep(INITIAL_EFFECT_DELAY_MILLIS);
+        if skip_intro {
+            return Self {
+                state,
+                effect_clock: EffectClock::default(),
+                effect,
+                fx_filter,
+            };
+        }
+
+        let initial_effect = tachyonfx::fx::coalesce(scaled(INITIAL_EFFECT_MILLIS));
+        let sleep = tachyonfx::fx::sleep(scaled(INITIAL_EFFECT_DELAY_MILLIS));
         let initial_effect = tachyonfx::fx::sequence(&[initial_effect, sleep]);
 
         let initial_effect = initial_effect.with_filter(fx_filter.main_filter());
@@ -130,7 +1000,7 @@ impl TuiState {
 
         Self {
             state,
-            last_instant,
+            effect_clock: EffectClock::default(),
             effect,
             fx_filter,
         }
@@ -147,12 +1017,7 @@ impl TuiState {
             }
         }
 
-        let now = Instant::now();
-        let elapsed = self
-            .last_instant
-            .map_or(std::time::Duration::ZERO, |last| now - last)
-            .into();
-        self.last_instant = Some(now);
+        let elapsed = self.effect_clock.tick(Instant::now());
 
         if theme.fx_enabled && self.effect.running() {
             frame.render_effect(&mut self.effect, frame.area(), elapsed);
@@ -165,102 +1030,359 @@ impl TuiState {
         let TuiDeepState::DisplayData(state) = &mut self.state else {
             anyhow::bail!("DisplayData state expected")
         };
+        state.clamp_selection();
         let items_strings = state
-            .eval
-            .iter()
-            .map(|e| format!("{} {:.3}", e.fragment.location(), e.value))
+            .rows()
+            .into_iter()
+            .map(|row| {
+                let label = if theme.score_markers {
+                    format!(
+                        "{} {}",
+                        score_marker(state.row_value(row)),
+                        state.row_label(row)
+                    )
+                } else {
+                    state.row_label(row)
+                };
+                (label, state.row_change(row))
+            })
             .collect::<Vec<_>>();
-        let max_len = items_strings.iter().map(|s| s.len()).max().unwrap_or(0);
-
-        let layout = ratatui::layout::Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([Constraint::Fill(1), Constraint::Length(max_len as u16 + 2)].as_ref())
-            .split(frame.area());
+        let max_len = items_strings
+            .iter()
+            .map(|(s, change)| {
+                s.chars().count() + change.map_or(0, |change| change.label().chars().count() + 1)
+            })
+            .max()
+            .unwrap_or(0);
 
-        for rect in layout.iter() {
-            self.fx_filter.assign(rect.inner(Margin::new(1, 1)))?;
+        let [area, slider_area] = ratatui::layout::Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Fill(1),
+                Constraint::Length(state.threshold.map_or(0, |_| 1)),
+            ])
+            .areas(frame.area());
+        if let Some(threshold) = state.threshold {
+            let label = format!(
+                "Minimum score {:.2}: {} of {} fragments, ←/→ to change",
+                threshold,
+                (0..state.eval.len())
+                    .filter(|&idx| state.shown(idx))
+                    .count(),
+                state.eval.len()
+            );
+            frame.render_widget(
+                Gauge::default()
+                    .gauge_style(theme.gauge_color(threshold as f64))
+                    .ratio(threshold as f64)
+                    .label(label.set_style(theme.text))
+                    .use_unicode(true)
+                    .bg(theme.background),
+                slider_area,
+            );
         }
 
-        let code = Self::make_code(
-            state.eval.get(state.current_idx).map(|e| &e.fragment),
-            theme,
-        );
+        let list_area = if state.list_only {
+            area
+        } else {
+            let layout = ratatui::layout::Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints(
+                    [
+                        Constraint::Fill(1),
+                        Constraint::Length((max_len as u16).saturating_add(2)),
+                    ]
+                    .as_ref(),
+                )
+                .split(area);
+
+            self.fx_filter.assign(layout[0].inner(Margin::new(1, 1)))?;
+
+            let code = Self::make_code(
+                state
+                    .selected_eval_idx()
+                    .and_then(|idx| state.eval.get(idx))
+                    .map(|e| &e.fragment),
+                state.wrap,
+                state.plain,
+                state.scroll_x,
+                theme,
+            );
+
+            let code_area = if state.show_summary {
+                let code_layout = ratatui::layout::Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Fill(1), Constraint::Percentage(40)].as_ref())
+                    .split(layout[0]);
+                self.fx_filter
+                    .assign(code_layout[1].inner(Margin::new(1, 1)))?;
+                let summary = Paragraph::new(state.summary())
+                    .wrap(Wrap { trim: false })
+                    .block(
+                        Block::bordered()
+                            .border_type(BorderType::Rounded)
+                            .set_style(theme.border)
+                            .title(" Top reasons ".set_style(theme.title).bold()),
+                    )
+                    .set_style(theme.text)
+                    .bg(theme.background);
+                frame.render_widget(summary, code_layout[1]);
+                code_layout[0]
+            } else {
+                layout[0]
+            };
+
+            frame.render_widget(code, code_area);
+
+            layout[1]
+        };
 
-        frame.render_widget(code, layout[0]);
+        self.fx_filter.assign(list_area.inner(Margin::new(1, 1)))?;
 
-        let items = items_strings.into_iter().map(ListItem::new);
+        let items = items_strings.into_iter().map(|(label, change)| {
+            let Some(change) = change else {
+                return ListItem::new(label);
+            };
+            let color = match change {
+                Change::Delta(delta) if delta > 0.0 => theme.rise,
+                Change::Delta(delta) if delta < 0.0 => theme.fall,
+                _ => theme.text,
+            };
+            ListItem::new(Line::from(vec![
+                format!("{} ", label).into(),
+                change.label().fg(color),
+            ]))
+        });
 
         let list = ratatui::widgets::List::new(items)
             .block(
                 Block::bordered()
                     .border_type(BorderType::Rounded)
                     .set_style(theme.border)
-                    .title(" Fragments ".set_style(theme.title).bold()),
+                    .title(" Fragments ".set_style(theme.title).bold())
+                    .title(
+                        state
+                            .position()
+                            .map(|position| format!(" {} ", position))
+                            .unwrap_or_default()
+                            .set_style(theme.text)
+                            .into_right_aligned_line(),
+                    )
+                    .title_bottom(
+                        state
+                            .search
+                            .as_ref()
+                            .map(|search| format!(" /{} ", search.query))
+                            .or_else(|| {
+                                state
+                                    .question
+                                    .as_ref()
+                                    .map(|question| format!(" Question: {} ", question))
+                            })
+                            .unwrap_or_default()
+                            .set_style(theme.highlight),
+                    )
+                    .title_bottom(
+                        state
+                            .status()
+                            .unwrap_or_default()
+                            .set_style(theme.text)
+                            .into_right_aligned_line(),
+                    ),
             )
             .set_style(theme.text)
             .highlight_style(theme.highlight)
             .bg(theme.background);
 
+        // page size follows the list area of the latest render, so it stays correct after a resize
+        state.page_size = (list_area.height as usize).saturating_sub(2).max(1);
         state.list_state.select(Some(state.current_idx));
 
-        frame.render_stateful_widget(list, layout[1], &mut state.list_state);
-
-        Ok(())
-    }
+        frame.render_stateful_widget(list, list_area, &mut state.list_state);
 
-    fn render_gather_data(&mut self, frame: &mut Frame, theme: Theme) -> anyhow::Result<()> {
-        let TuiDeepState::GatherData(state) = &mut self.state else {
+        if state.show_raw {
+            let area = frame.area();
+            let popup_area = area.inner(Margin::new(area.width / 8, area.height / 6));
+            self.fx_filter.assign(popup_area.inner(Margin::new(1, 1)))?;
+            let raw = state
+                .selected_eval_idx()
+                .and_then(|idx| state.eval[idx].raw.clone())
+                .unwrap_or_else(|| "No response".to_string());
+            let popup = Paragraph::new(raw)
+                .wrap(Wrap { trim: false })
+                .block(
+                    Block::bordered()
+                        .border_type(BorderType::Rounded)
+                        .set_style(theme.border)
+                        .title(" Raw response ".set_style(theme.title).bold()),
+                )
+                .set_style(theme.text)
+                .bg(theme.background);
+            frame.render_widget(Clear, popup_area);
+            frame.render_widget(popup, popup_area);
+        }
+
+        if let Some(explanation) = &state.explanation {
+            let area = frame.area();
+            let popup_area = area.inner(Margin::new(area.width / 8, area.height / 6));
+            self.fx_filter.assign(popup_area.inner(Margin::new(1, 1)))?;
+            let text = match &explanation.answer {
+                None => "Asking the model…".to_string(),
+                Some(Ok(text)) => text.clone(),
+                Some(Err(e)) => format!("Explanation failed: {}", e),
+            };
+            let popup = Paragraph::new(text)
+                .wrap(Wrap { trim: false })
+                .block(
+                    Block::bordered()
+                        .border_type(BorderType::Rounded)
+                        .set_style(theme.border)
+                        .title(" Explanation ".set_style(theme.title).bold())
+                        .title_bottom(
+                            format!(" {} ", explanation.location)
+                                .set_style(theme.text)
+                                .into_right_aligned_line(),
+                        ),
+                )
+                .set_style(theme.text)
+                .bg(theme.background);
+            frame.render_widget(Clear, popup_area);
+            frame.render_widget(popup, popup_area);
+        }
+
+        Ok(())
+    }
+
+    fn render_gather_data(&mut self, frame: &mut Frame, theme: Theme) -> anyhow::Result<()> {
+        let TuiDeepState::GatherData(state) = &mut self.state else {
             anyhow::bail!("GatherData state expected")
         };
+        if state.compact || frame.area().height < COMPACT_GATHER_HEIGHT {
+            return self.render_gather_data_compact(frame, theme);
+        }
+        let constraints = if state.code_only {
+            [
+                Constraint::Fill(1),
+                Constraint::Length(0),
+                Constraint::Length(1),
+            ]
+        } else {
+            [
+                Constraint::Fill(1),
+                Constraint::Length(4),
+                Constraint::Length(5),
+            ]
+        };
         let layout = ratatui::layout::Layout::default()
             .direction(Direction::Vertical)
-            .constraints(
-                [
-                    Constraint::Fill(1),
-                    Constraint::Length(4),
-                    Constraint::Length(5),
-                ]
-                .as_ref(),
-            )
+            .constraints(constraints.as_ref())
             .split(frame.area());
 
         for rect in layout.iter() {
             self.fx_filter.assign(rect.inner(Margin::new(1, 1)))?;
         }
 
+        let code_area = if state.partial_response.is_empty() {
+            layout[0]
+        } else {
+            let code_layout = ratatui::layout::Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Fill(1), Constraint::Length(6)].as_ref())
+                .split(layout[0]);
+            self.fx_filter
+                .assign(code_layout[1].inner(Margin::new(1, 1)))?;
+            let response = Paragraph::new(state.partial_response.as_str())
+                .wrap(Wrap { trim: false })
+                .block(
+                    Block::bordered()
+                        .border_type(BorderType::Rounded)
+                        .set_style(theme.border)
+                        .title(" Response ".set_style(theme.title).bold()),
+                )
+                .set_style(theme.text)
+                .bg(theme.background);
+            frame.render_widget(response, code_layout[1]);
+            code_layout[0]
+        };
+
         let current_fragment = state.current_fragment.as_ref();
 
-        let code = Self::make_code(current_fragment, theme);
+        let code = Self::make_code(current_fragment, true, false, 0, theme);
 
-        frame.render_widget(code, layout[0]);
+        frame.render_widget(code, code_area);
 
+        if state.code_only {
+            let label = match state.quit_prompt() {
+                Some(prompt) => prompt.trim().to_string(),
+                None => state.progress_label(),
+            };
+            frame.render_widget(
+                Gauge::default()
+                    .gauge_style(theme.gauge_color(state.ratio()))
+                    .ratio(state.ratio())
+                    .label(label.set_style(theme.text))
+                    .use_unicode(true)
+                    .bg(theme.background),
+                layout[2],
+            );
+            return Ok(());
+        }
+
+        // braille markers give two data points per cell inside the chart borders
+        let chart_points = (layout[1].width as usize).saturating_sub(2).max(1) * 2;
         let data: Vec<_> = state
-            .value_history
-            .iter()
-            .copied()
-            .rev()
-            .take((layout[1].width as usize - 2) * 2)
-            .rev()
+            .chart_values(chart_points)
+            .into_iter()
             .enumerate()
             .map(|(idx, val)| (idx as f64, val as f64))
             .collect();
+        let mean_data: Vec<_> = state
+            .mean()
+            .map(|mean| vec![(0.0, mean as f64), (chart_points as f64 - 1.0, mean as f64)])
+            .unwrap_or_default();
+        let latest_data: Vec<_> = data.last().copied().into_iter().collect();
         let data = vec![
+            Dataset::default()
+                .marker(Marker::Braille)
+                .graph_type(GraphType::Line)
+                .style(theme.title)
+                .data(&mean_data),
             Dataset::default()
                 .marker(Marker::Braille)
                 .style(theme.text)
                 .data(&data),
+            Dataset::default()
+                .marker(Marker::Dot)
+                .style(theme.highlight)
+                .data(&latest_data),
         ];
 
         let chart = Chart::new(data)
             .block(
                 Block::bordered()
                     .border_type(BorderType::Rounded)
-                    .title(" Value history ".set_style(theme.title).bold()),
+                    .title(
+                        if state.full_history {
+                            " Value history (all) "
+                        } else {
+                            " Value history "
+                        }
+                        .set_style(theme.title)
+                        .bold(),
+                    )
+                    .title(
+                        state
+                            .mean()
+                            .map(|mean| format!(" mean {:.3} ", mean))
+                            .unwrap_or_default()
+                            .set_style(theme.text)
+                            .into_right_aligned_line(),
+                    ),
             )
             .x_axis(
                 Axis::default()
                     .style(theme.text)
-                    .bounds([0.0, (layout[1].width as f64 - 2.0) * 2.0 - 1.0]),
+                    .bounds([0.0, chart_points as f64 - 1.0]),
             )
             .y_axis(Axis::default().style(theme.text).bounds([0.0, 1.0]))
             .style(theme.border)
@@ -270,15 +1392,30 @@ impl TuiState {
 
         frame.render_widget(
             Gauge::default()
-                .gauge_style(theme.gauge)
+                .gauge_style(theme.gauge_color(state.ratio()))
                 .block(
                     Block::bordered()
                         .set_style(theme.border)
                         .border_type(BorderType::Rounded)
-                        .title(" Progress ".set_style(theme.title).bold()),
+                        .title(" Progress ".set_style(theme.title).bold())
+                        .title_bottom(
+                            state
+                                .last_failure
+                                .as_ref()
+                                .map(|failure| format!(" {} ", failure))
+                                .unwrap_or_default()
+                                .set_style(theme.highlight),
+                        )
+                        .title_bottom(
+                            state
+                                .quit_prompt()
+                                .unwrap_or_default()
+                                .set_style(theme.highlight)
+                                .into_right_aligned_line(),
+                        ),
                 )
-                .ratio(state.count as f64 / state.count_max as f64)
-                .label(format!("{}/{}", state.count, state.count_max).set_style(theme.text))
+                .ratio(state.ratio())
+                .label(state.progress_label().set_style(theme.text))
                 .use_unicode(true)
                 .bg(theme.background),
             layout[2],
@@ -287,24 +1424,81 @@ impl TuiState {
         Ok(())
     }
 
-    fn make_code(current_fragment: Option<&Fragment>, theme: Theme) -> Paragraph<'static> {
+    fn render_gather_data_compact(
+        &mut self,
+        frame: &mut Frame,
+        theme: Theme,
+    ) -> anyhow::Result<()> {
+        let TuiDeepState::GatherData(state) = &self.state else {
+            anyhow::bail!("GatherData state expected")
+        };
+        let layout = ratatui::layout::Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(1), Constraint::Length(1)].as_ref())
+            .split(frame.area());
+
+        for rect in layout.iter() {
+            self.fx_filter.assign(*rect)?;
+        }
+
+        frame.render_widget(Block::new().bg(theme.background), frame.area());
+
+        let location = match state.quit_prompt() {
+            Some(prompt) => prompt.trim().to_string(),
+            None => state
+                .current_fragment
+                .as_ref()
+                .map(|fragment| fragment.location())
+                .unwrap_or_default(),
+        };
+        frame.render_widget(
+            Paragraph::new(location.set_style(theme.title).bold()),
+            layout[0],
+        );
+
+        frame.render_widget(
+            Gauge::default()
+                .gauge_style(theme.gauge_color(state.ratio()))
+                .ratio(state.ratio())
+                .label(state.progress_label().set_style(theme.text))
+                .use_unicode(true)
+                .bg(theme.background),
+            layout[1],
+        );
+
+        Ok(())
+    }
+
+    fn make_code(
+        current_fragment: Option<&Fragment>,
+        wrap: bool,
+        plain: bool,
+        scroll_x: u16,
+        theme: Theme,
+    ) -> Paragraph<'static> {
         match current_fragment {
             Some(fragment) => {
-                let lines = fragment.highlighted_content();
-                let code = Paragraph::new(lines).wrap(Wrap { trim: false });
-                let code = code
-                    .block(
-                        Block::bordered()
-                            .border_type(BorderType::Rounded)
-                            .set_style(theme.border)
-                            .title(
-                                format!(" {} ", fragment.location())
-                                    .set_style(theme.title)
-                                    .bold(),
-                            ),
-                    )
-                    .bg(theme.background);
-                code
+                let code = if plain {
+                    Paragraph::new(fragment.plain_content()).fg(theme.text)
+                } else {
+                    Paragraph::new(fragment.highlighted_content())
+                };
+                let code = if wrap {
+                    code.wrap(Wrap { trim: false })
+                } else {
+                    code.scroll((0, scroll_x))
+                };
+                code.block(
+                    Block::bordered()
+                        .border_type(BorderType::Rounded)
+                        .set_style(theme.border)
+                        .title(
+                            format!(" {} ({}) ", fragment.location(), fragment.language())
+                                .set_style(theme.title)
+                                .bold(),
+                        ),
+                )
+                .bg(theme.background)
             }
             None => Paragraph::new("").block(
                 Block::bordered()
@@ -325,41 +1519,218 @@ pub enum Nav {
     PageDown,
     Home,
     End,
+    Left,
+    Right,
+    PrevFile,
+    NextFile,
+    /// Highest scoring fragment, independent of the sorting.
+    Best,
+    /// Lowest scoring fragment, independent of the sorting.
+    Worst,
+}
+
+#[derive(Debug, Clone)]
+pub enum Search {
+    Start,
+    Input(char),
+    Backspace,
+    Confirm,
+    Cancel,
 }
 
 #[derive(Debug, Clone)]
 pub enum TuiEvent {
     Render,
     GatherNextFragment(Fragment),
+    GatherPartialResponse(String),
     GatherNextValue(f32),
     GatherIncrementCount,
+    /// Location and error message of a failed query.
+    GatherFailure(String, String),
     SwitchToDisplayData(Vec<FragmentEvaluation>),
+    /// Gathering starts again for a new question, with the given number of fragments.
+    SwitchToGatherData(usize),
+    /// Number of fragments removed from changed files, shown with the results.
+    Removed(usize),
     Nav(Nav),
+    ToggleGrouped,
+    ToggleExpanded,
+    HideSelected,
+    Unhide,
+    Search(Search),
+    /// Typing a new question, with the same keys as the search.
+    Question(Search),
+    ToggleWrap,
+    ToggleSummary,
+    ToggleRaw,
+    TogglePlain,
+    ToggleListOnly,
+    ToggleThreshold,
+    ToggleFullHistory,
+    ToggleCodeOnly,
+    ConfirmQuit,
+    ToggleSortBy,
+    ToggleSortOrder,
+    Requery,
+    Requeried {
+        location: String,
+        result: Result<QueryResult, String>,
+    },
+    /// Opens or closes the explanation of the selected fragment.
+    Explain,
+    Explained {
+        location: String,
+        result: Result<String, String>,
+    },
     Quit,
 }
 
+/// Parameters of the border highlight sweep.
+#[derive(Debug, Clone, Copy)]
+pub struct EffectOptions {
+    /// Width of the highlighted band in cells.
+    pub width: f32,
+    /// Lightness added in the middle of the band.
+    pub strength: f32,
+    /// Factor applied to the speed of all effects and the pauses between them.
+    pub speed: f32,
+    /// Start with the border sweep instead of the coalesce intro and the pause after it.
+    pub skip_intro: bool,
+}
+
+impl Default for EffectOptions {
+    fn default() -> Self {
+        Self {
+            width: EFFECT_WIDTH,
+            strength: EFFECT_STRENGTH,
+            speed: 1.0,
+            skip_intro: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TuiOptions {
+    pub sort_by: SortBy,
+    pub sort_order: SortOrder,
+    /// Results view state restored once gathering is done.
+    pub view: ViewState,
+    /// Always use the compact gather view, not only on short terminals.
+    pub compact: bool,
+    /// Plot the whole value history downsampled to the chart width instead of the latest values.
+    pub full_history: bool,
+    /// Start the results view without the code pane.
+    pub list_only: bool,
+    pub effect: EffectOptions,
+    /// Show the gathering progress in the terminal title.
+    pub title_progress: bool,
+    /// Normalization of the gathered values, repeated for requeried values.
+    pub normalize: Option<Normalize>,
+}
+
+/// Restores the terminal when dropped, also if the tui task is cancelled or unwinds.
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+/// Saves the terminal title on the terminal's title stack and restores it when dropped.
+struct TitleGuard;
+
+impl TitleGuard {
+    fn new() -> std::io::Result<Self> {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(b"\x1b[22;0t")?;
+        stdout.flush()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for TitleGuard {
+    fn drop(&mut self) {
+        let mut stdout = std::io::stdout();
+        // terminals without a title stack ignore the sequence, so clear the title first
+        let _ = crossterm::execute!(stdout, SetTitle(""));
+        let _ = stdout.write_all(b"\x1b[23;0t");
+        let _ = stdout.flush();
+    }
+}
+
+/// Requests from the results view, or the file watcher, to the main flow.
+#[derive(Debug, Clone)]
+pub enum TuiRequest {
+    Requery(Fragment),
+    /// Ask the model to explain its view of the fragment in prose.
+    Explain(Fragment),
+    /// Evaluate the same fragments for a new question.
+    Ask(String),
+    /// Files changed on disk, their fragments replace the previous ones and are evaluated.
+    Reload {
+        paths: Vec<PathBuf>,
+        fragments: Vec<Fragment>,
+    },
+}
+
 #[derive(Debug)]
 pub struct Tui {
     tui_state: TuiState,
     theme: Theme,
+    tx_request: UnboundedSender<TuiRequest>,
+    options: TuiOptions,
+    /// Percentage last written to the terminal title.
+    title_percent: Option<u8>,
 }
 
 impl Tui {
-    pub fn new(count_max: usize, theme: Theme) -> Self {
-        let tui_state = TuiState::new(count_max);
-        Self { tui_state, theme }
+    pub fn new(
+        count_max: usize,
+        theme: Theme,
+        tx_request: UnboundedSender<TuiRequest>,
+        options: TuiOptions,
+    ) -> Self {
+        let tui_state = TuiState::new(
+            count_max,
+            options.compact,
+            options.full_history,
+            options.effect,
+        );
+        Self {
+            tui_state,
+            theme,
+            tx_request,
+            options,
+            title_percent: None,
+        }
     }
 
     fn render(&mut self, terminal: &mut DefaultTerminal) -> anyhow::Result<()> {
-        terminal.draw(|frame| {
+        // render errors end the tui through the normal path, so the terminal is restored before
+        // the error is printed
+        terminal.try_draw(|frame| {
             self.tui_state
                 .render(frame, self.theme)
-                .expect("Rendering expected")
+                .map_err(std::io::Error::other)
         })?;
 
         Ok(())
     }
 
+    fn update_title(&mut self) -> anyhow::Result<()> {
+        let TuiDeepState::GatherData(state) = &self.tui_state.state else {
+            return Ok(());
+        };
+        let percent = state.percent();
+        if self.options.title_progress && self.title_percent != Some(percent) {
+            crossterm::execute!(std::io::stdout(), SetTitle(format!("grepowski {percent}%")))?;
+            self.title_percent = Some(percent);
+        }
+
+        Ok(())
+    }
+
     async fn main_loop(
         &mut self,
         mut rx: tokio::sync::mpsc::Receiver<TuiEvent>,
@@ -381,6 +1752,11 @@ impl Tui {
                         Some(TuiEvent::GatherNextFragment(fragment)) => {
                             let TuiDeepState::GatherData(state) = &mut self.tui_state.state else { break Err(anyhow::anyhow!("GatherData state expected"))};
                             state.current_fragment = Some(fragment);
+                            state.partial_response.clear();
+                        },
+                        Some(TuiEvent::GatherPartialResponse(partial_response)) => {
+                            let TuiDeepState::GatherData(state) = &mut self.tui_state.state else { break Err(anyhow::anyhow!("GatherData state expected"))};
+                            state.partial_response = partial_response;
                         },
                         Some(TuiEvent::GatherNextValue(value)) => {
                             let TuiDeepState::GatherData(state) = &mut self.tui_state.state else { break Err(anyhow::anyhow!("GatherData state expected"))};
@@ -389,37 +1765,152 @@ impl Tui {
                         Some(TuiEvent::GatherIncrementCount) => {
                             let TuiDeepState::GatherData(state) = &mut self.tui_state.state else { break Err(anyhow::anyhow!("GatherData state expected"))};
                             state.count += 1;
+                            self.update_title()?;
+                        },
+                        Some(TuiEvent::GatherFailure(location, message)) => {
+                            let TuiDeepState::GatherData(state) = &mut self.tui_state.state else { break Err(anyhow::anyhow!("GatherData state expected"))};
+                            state.failures += 1;
+                            state.last_failure = Some(format!("{}: {}", location, message));
                         },
                         Some(TuiEvent::SwitchToDisplayData(data)) => {
-                            self.tui_state.state = TuiDeepState::DisplayData(DisplayDataState::new(data));
+                            let mut state = DisplayDataState::new(data, self.options.sort_by, self.options.sort_order);
+                            state.apply_view(&self.options.view);
+                            state.list_only = self.options.list_only;
+                            state.normalize = self.options.normalize;
+                            self.tui_state.state = TuiDeepState::DisplayData(state);
+                        }
+                        Some(TuiEvent::SwitchToGatherData(count_max)) => {
+                            // the selection and grouping survive gathering again
+                            if let TuiDeepState::DisplayData(state) = &self.tui_state.state {
+                                self.options.view = state.view_state();
+                            }
+                            self.tui_state.state = TuiDeepState::GatherData(GatherDataState::new(
+                                count_max,
+                                self.options.compact,
+                                self.options.full_history,
+                            ));
+                            self.update_title()?;
                         }
                         Some(TuiEvent::Quit) | None => {
                             return Ok(())
                         },
                         Some(TuiEvent::Nav(nav)) => {
                             if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
-                                match nav {
-                                    Nav::Up => {
-                                    state.current_idx = state.current_idx.saturating_sub(1);
-                                    }
-                                    Nav::Down => {
-                                            state.current_idx = std::cmp::min(state.current_idx.saturating_add(1), state.eval.len() - 1);
-                                        }
-                                    Nav::PageUp | Nav::PageDown => {
-                                        let items = terminal.get_frame().area().height as usize - 2;
-                                            match nav {
-                                                Nav::PageUp => state.current_idx = state.current_idx.saturating_sub(items),
-                                                Nav::PageDown => state.current_idx = std::cmp::min(state.current_idx.saturating_add(items), state.eval.len() - 1),
-                                                _ => unreachable!()
-                                            }
-                                    }
-                                    Nav::Home => {
-                                            state.current_idx = 0;
-                                        }
-                                    Nav::End => {
-                                            state.current_idx = state.eval.len() - 1;
-                                        }
-                                }
+                                state.nav(nav);
+                            }
+                        }
+                        Some(TuiEvent::ToggleGrouped) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.toggle_grouped();
+                            }
+                        }
+                        Some(TuiEvent::ToggleExpanded) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.toggle_expanded();
+                            }
+                        }
+                        Some(TuiEvent::HideSelected) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.hide_selected();
+                            }
+                        }
+                        Some(TuiEvent::Unhide) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.unhide();
+                            }
+                        }
+                        Some(TuiEvent::ToggleWrap) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.wrap = !state.wrap;
+                            }
+                        }
+                        Some(TuiEvent::ToggleSummary) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.show_summary = !state.show_summary;
+                            }
+                        }
+                        Some(TuiEvent::ToggleRaw) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.show_raw = !state.show_raw;
+                            }
+                        }
+                        Some(TuiEvent::TogglePlain) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.plain = !state.plain;
+                            }
+                        }
+                        Some(TuiEvent::ToggleListOnly) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.list_only = !state.list_only;
+                            }
+                        }
+                        Some(TuiEvent::ToggleThreshold) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.toggle_threshold();
+                            }
+                        }
+                        Some(TuiEvent::ToggleFullHistory) => {
+                            if let TuiDeepState::GatherData(state) = &mut self.tui_state.state {
+                                state.full_history = !state.full_history;
+                            }
+                        }
+                        Some(TuiEvent::ToggleCodeOnly) => {
+                            if let TuiDeepState::GatherData(state) = &mut self.tui_state.state {
+                                state.code_only = !state.code_only;
+                            }
+                        }
+                        Some(TuiEvent::ConfirmQuit) => {
+                            if let TuiDeepState::GatherData(state) = &mut self.tui_state.state {
+                                state.quit_prompt_until = Some(Instant::now() + QUIT_CONFIRM_WINDOW);
+                            }
+                        }
+                        Some(TuiEvent::ToggleSortBy) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                let sort_by = state.sort_by.next();
+                                state.set_sort(sort_by, sort_by.default_order());
+                            }
+                        }
+                        Some(TuiEvent::ToggleSortOrder) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.set_sort(state.sort_by, state.sort_order.reversed());
+                            }
+                        }
+                        Some(TuiEvent::Requery) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state
+                                && let Some(fragment) = state.start_requery() {
+                                self.tx_request.send(TuiRequest::Requery(fragment))?;
+                            }
+                        }
+                        Some(TuiEvent::Explain) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state
+                                && let Some(fragment) = state.start_explain() {
+                                self.tx_request.send(TuiRequest::Explain(fragment))?;
+                            }
+                        }
+                        Some(TuiEvent::Explained { location, result }) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.finish_explain(&location, result);
+                            }
+                        }
+                        Some(TuiEvent::Removed(removed)) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.removed = removed;
+                            }
+                        }
+                        Some(TuiEvent::Requeried { location, result }) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.finish_requery(&location, result);
+                            }
+                        }
+                        Some(TuiEvent::Search(search)) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state {
+                                state.search(search);
+                            }
+                        }
+                        Some(TuiEvent::Question(input)) => {
+                            if let TuiDeepState::DisplayData(state) = &mut self.tui_state.state
+                                && let Some(question) = state.question(input) {
+                                self.tx_request.send(TuiRequest::Ask(question))?;
                             }
                         }
                     }
@@ -428,13 +1919,444 @@ impl Tui {
         }
     }
 
-    pub async fn run(mut self, rx: tokio::sync::mpsc::Receiver<TuiEvent>) -> anyhow::Result<()> {
+    /// Runs until quit, returns the final state of the results view if it was reached.
+    pub async fn run(
+        mut self,
+        rx: tokio::sync::mpsc::Receiver<TuiEvent>,
+    ) -> anyhow::Result<Option<ViewState>> {
+        // besides entering raw mode and the alternate screen, this installs a panic hook restoring
+        // the terminal before the panic message and backtrace are printed
         let mut terminal = ratatui::init();
+        let guard = TerminalGuard;
+        let title_guard = if self.options.title_progress {
+            Some(TitleGuard::new()?)
+        } else {
+            None
+        };
 
-        let result = self.main_loop(rx, &mut terminal).await;
+        let result = match self.update_title() {
+            Ok(()) => self.main_loop(rx, &mut terminal).await,
+            Err(e) => Err(e),
+        };
 
-        ratatui::restore();
+        drop(title_guard);
+        drop(guard);
+
+        result?;
+
+        Ok(match &self.tui_state.state {
+            TuiDeepState::DisplayData(state) => Some(state.view_state()),
+            TuiDeepState::GatherData(_) => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grepowski::fragment::{FragmentSize, file_to_fragments};
+    use tempfile::tempdir;
+
+    /// One fragment per line of the given `(name, content)` files, with `values` in that order.
+    fn evaluations(
+        files: &[(&str, &str)],
+        values: &[f32],
+    ) -> anyhow::Result<Vec<FragmentEvaluation>> {
+        let dir = tempdir()?;
+        let size = FragmentSize::Lines {
+            lines_per_block: 1,
+            blocks_per_fragment: 1,
+        };
+        let mut fragments = Vec::new();
+        for (name, content) in files {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content)?;
+            fragments.extend(file_to_fragments(&path, size, Theme::synthwave())?);
+        }
+        Ok(fragments
+            .into_iter()
+            .zip(values)
+            .map(|(fragment, &value)| FragmentEvaluation {
+                fragment,
+                value,
+                reason: None,
+                std_dev: None,
+                raw: None,
+                raw_value: None,
+                change: None,
+            })
+            .collect())
+    }
+
+    #[test]
+    fn grouped_view_collapses_fragments_by_file() -> anyhow::Result<()> {
+        let eval = evaluations(
+            &[
+                ("a.rs", "fn a1() {}\nfn a2() {}\n"),
+                ("b.rs", "fn b1() {}\n"),
+            ],
+            &[0.9, 0.5, 0.1],
+        )?;
+        let mut state = DisplayDataState::new(eval, SortBy::Score, SortOrder::Desc);
+        state.toggle_grouped();
+        assert_eq!(state.rows(), vec![DisplayRow::File(0), DisplayRow::File(1)]);
+
+        state.toggle_expanded();
+        assert_eq!(
+            state.rows(),
+            vec![
+                DisplayRow::File(0),
+                DisplayRow::Fragment(0),
+                DisplayRow::Fragment(1),
+                DisplayRow::File(1)
+            ]
+        );
+
+        state.current_idx = 2;
+        state.toggle_grouped();
+        assert_eq!(state.selected_eval_idx(), Some(1));
+
+        state.current_idx = 2;
+        state.set_sort(SortBy::Location, SortOrder::Asc);
+        assert_eq!(state.current_idx, 2);
+        assert!(
+            state.eval[state.current_idx]
+                .fragment
+                .location()
+                .ends_with("b.rs:0")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn nav_jumps_between_files() -> anyhow::Result<()> {
+        let eval = evaluations(
+            &[
+                ("a.rs", "fn a1() {}\nfn a2() {}\n"),
+                ("b.rs", "fn b1() {}\n"),
+            ],
+            &[0.5; 3],
+        )?;
+
+        let mut state = DisplayDataState::new(eval, SortBy::Location, SortOrder::Asc);
+        state.nav(Nav::NextFile);
+        assert_eq!(state.current_idx, 2);
+        state.nav(Nav::NextFile);
+        assert_eq!(state.current_idx, 2);
+        state.nav(Nav::PrevFile);
+        assert_eq!(state.current_idx, 0);
+        state.current_idx = 1;
+        state.nav(Nav::PrevFile);
+        assert_eq!(state.current_idx, 0);
+
+        state.eval[1].value = 0.9;
+        state.eval[2].value = 0.1;
+        state.nav(Nav::Best);
+        assert_eq!(state.current_idx, 1);
+        state.nav(Nav::Worst);
+        assert_eq!(state.current_idx, 2);
+        state.toggle_grouped();
+        state.nav(Nav::Best);
+        assert_eq!(state.rows()[state.current_idx], DisplayRow::File(0));
+        Ok(())
+    }
+
+    #[test]
+    fn hidden_fragments_can_be_shown_again() -> anyhow::Result<()> {
+        let eval = evaluations(
+            &[("a.rs", "fn a1() {}\nfn a2() {}\nfn a3() {}\n")],
+            &[0.1, 0.5, 0.9],
+        )?;
+
+        let mut state = DisplayDataState::new(eval, SortBy::Score, SortOrder::Desc);
+        state.current_idx = 2;
+        state.hide_selected();
+        assert_eq!(state.current_idx, 1);
+        assert_eq!(state.eval[1].value, 0.5);
+        state.hide_selected();
+        assert_eq!(state.current_idx, 0);
+        state.hide_selected();
+        assert_eq!(state.eval.len(), 1);
+        assert_eq!(state.status(), Some(" 2 hidden ".to_string()));
+
+        state.unhide();
+        assert_eq!(state.eval.len(), 2);
+        assert_eq!(state.eval[state.current_idx].value, 0.5);
+        state.unhide();
+        state.unhide();
+        assert_eq!(state.eval[state.current_idx].value, 0.1);
+        assert_eq!(state.current_idx, 2);
+        assert_eq!(state.status(), None);
+
+        state.toggle_grouped();
+        state.hide_selected();
+        assert_eq!(state.eval.len(), 3);
+        state.toggle_expanded();
+        state.nav(Nav::Down);
+        state.hide_selected();
+        assert_eq!(state.eval.len(), 2);
+        assert_eq!(state.rows().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn horizontal_scroll_resets_when_the_selection_changes() -> anyhow::Result<()> {
+        let long = format!("fn a1() {{ {} }}\nfn a2() {{}}\n", "x".repeat(100));
+        let eval = evaluations(&[("a.rs", long.as_str())], &[0.9, 0.5])?;
+
+        let mut state = DisplayDataState::new(eval, SortBy::Score, SortOrder::Desc);
+        state.nav(Nav::Right);
+        assert_eq!(state.scroll_x, HORIZONTAL_SCROLL_STEP);
+        state.nav(Nav::Up);
+        assert_eq!(state.scroll_x, HORIZONTAL_SCROLL_STEP);
+        state.nav(Nav::Down);
+        assert_eq!(state.scroll_x, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn threshold_filters_rows_and_keeps_selection() -> anyhow::Result<()> {
+        let eval = evaluations(
+            &[
+                ("a.rs", "fn a1() {}\nfn a2() {}\n"),
+                ("b.rs", "fn b1() {}\n"),
+            ],
+            &[0.9, 0.1, 0.05],
+        )?;
+
+        let mut state = DisplayDataState::new(eval, SortBy::Score, SortOrder::Desc);
+        state.current_idx = 1;
+        state.toggle_threshold();
+        assert_eq!(state.rows().len(), 3);
+        state.nav(Nav::Right);
+        assert_eq!(state.threshold, Some(0.05));
+        assert_eq!(state.rows().len(), 3);
+        state.nav(Nav::Right);
+        assert_eq!(state.rows().len(), 2);
+        assert_eq!(state.selected_eval_idx(), Some(1));
+        state.nav(Nav::Worst);
+        assert_eq!(state.selected_eval_idx(), Some(1));
+
+        state.toggle_grouped();
+        assert_eq!(state.rows(), vec![DisplayRow::File(0)]);
+        assert!(state.row_label(DisplayRow::File(0)).ends_with("(2) ▇▂"));
+
+        state.nav(Nav::Right);
+        state.toggle_grouped();
+        assert_eq!(state.rows(), vec![DisplayRow::Fragment(0)]);
+        assert_eq!(state.current_idx, 0);
+
+        state.toggle_threshold();
+        assert_eq!(state.threshold, None);
+        assert_eq!(state.rows().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn view_state_keeps_the_threshold() -> anyhow::Result<()> {
+        let eval = evaluations(
+            &[("a.rs", "fn a1() {}\nfn a2() {}\nfn a3() {}\n")],
+            &[0.9, 0.5, 0.1],
+        )?;
+        let mut state = DisplayDataState::new(eval.clone(), SortBy::Score, SortOrder::Desc);
+        state.set_threshold(Some(0.3));
+        state.nav(Nav::End);
+        let view = state.view_state();
+        assert_eq!(view.threshold, Some(0.3));
+
+        let mut restored = DisplayDataState::new(eval, SortBy::Score, SortOrder::Desc);
+        restored.apply_view(&view);
+        assert_eq!(restored.threshold, Some(0.3));
+        assert_eq!(restored.rows().len(), 2);
+        assert_eq!(restored.current_idx, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn explanation_is_shown_for_its_fragment_only() -> anyhow::Result<()> {
+        let eval = evaluations(&[("a.rs", "fn a1() {}\n")], &[0.5])?;
+
+        let mut state = DisplayDataState::new(eval, SortBy::Score, SortOrder::Desc);
+        let location = state
+            .start_explain()
+            .map(|fragment| fragment.location())
+            .expect("fragment to explain");
+        assert!(
+            state
+                .explanation
+                .as_ref()
+                .is_some_and(|e| e.answer.is_none())
+        );
+
+        state.finish_explain("other.rs:0", Ok("no".to_string()));
+        assert!(
+            state
+                .explanation
+                .as_ref()
+                .is_some_and(|e| e.answer.is_none())
+        );
+        state.finish_explain(&location, Ok("because".to_string()));
+        assert_eq!(
+            state.explanation.as_ref().and_then(|e| e.answer.clone()),
+            Some(Ok("because".to_string()))
+        );
+
+        assert!(state.start_explain().is_none());
+        assert!(state.explanation.is_none());
+        state.finish_explain(&location, Ok("late".to_string()));
+        assert!(state.explanation.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn effect_clock_carries_sub_millisecond_remainder() {
+        let start = Instant::now();
+        let mut clock = EffectClock::default();
+        assert_eq!(clock.tick(start), tachyonfx::Duration::ZERO);
+        let total: u32 = (1..=10)
+            .map(|idx| {
+                clock
+                    .tick(start + std::time::Duration::from_micros(600 * idx))
+                    .milliseconds
+            })
+            .sum();
+        assert_eq!(total, 6);
+    }
+
+    #[test]
+    fn question_is_returned_when_confirmed() {
+        let mut state = DisplayDataState::new(Vec::new(), SortBy::Score, SortOrder::Desc);
+        assert_eq!(state.question(Search::Start), None);
+        for c in " why? ".chars() {
+            assert_eq!(state.question(Search::Input(c)), None);
+        }
+        assert_eq!(state.question(Search::Confirm), Some("why?".to_string()));
+        assert_eq!(state.question, None);
+
+        state.question(Search::Start);
+        assert_eq!(state.question(Search::Confirm), None);
+    }
+
+    #[test]
+    fn nav_stays_within_rows() {
+        let mut state = DisplayDataState::new(Vec::new(), SortBy::Score, SortOrder::Desc);
+        state.nav(Nav::End);
+        assert_eq!(state.current_idx, 0);
+        state.nav(Nav::PageDown);
+        assert_eq!(state.current_idx, 0);
+        state.nav(Nav::Up);
+        assert_eq!(state.current_idx, 0);
+    }
+
+    #[test]
+    fn render_survives_tiny_terminal() -> anyhow::Result<()> {
+        let mut terminal = ratatui::Terminal::new(ratatui::backend::TestBackend::new(2, 2))?;
+        let mut tui_state = TuiState::new(0, false, false, EffectOptions::default());
+        terminal.draw(|frame| {
+            tui_state
+                .render(frame, Theme::synthwave())
+                .expect("Rendering expected")
+        })?;
+        tui_state.state = TuiDeepState::DisplayData(DisplayDataState::new(
+            Vec::new(),
+            SortBy::Score,
+            SortOrder::Desc,
+        ));
+        terminal.draw(|frame| {
+            tui_state
+                .render(frame, Theme::synthwave())
+                .expect("Rendering expected")
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn code_only_leaves_a_progress_line() -> anyhow::Result<()> {
+        let mut terminal = ratatui::Terminal::new(ratatui::backend::TestBackend::new(40, 20))?;
+        let mut tui_state = TuiState::new(4, false, false, EffectOptions::default());
+        if let TuiDeepState::GatherData(state) = &mut tui_state.state {
+            state.code_only = true;
+            state.count = 1;
+        }
+        terminal.draw(|frame| {
+            tui_state
+                .render(frame, Theme::synthwave())
+                .expect("Rendering expected")
+        })?;
+        let buffer = terminal.backend().buffer();
+        let row = |y: u16| (0..40).map(|x| buffer[(x, y)].symbol()).collect::<String>();
+        assert!(row(19).contains("1/4"));
+        assert!((0..19).all(|y| !row(y).contains("Value history")));
+        Ok(())
+    }
+
+    #[test]
+    fn chart_values_downsample_full_history() {
+        let mut state = GatherDataState::new(6, false, false);
+        state.value_history.extend([0.0, 0.2, 0.4, 0.6, 0.8, 1.0]);
+        assert_eq!(state.chart_values(2), [0.8, 1.0]);
+        assert_eq!(state.chart_values(8).len(), 6);
+
+        state.full_history = true;
+        let values = state.chart_values(3);
+        assert_eq!(values.len(), 3);
+        assert!((values[0] - 0.1).abs() < 1e-6);
+        assert!((values[2] - 0.9).abs() < 1e-6);
+    }
+
+    #[test]
+    fn position_shows_rank_and_percentile() -> anyhow::Result<()> {
+        assert_eq!(ordinal_suffix(1), "st");
+        assert_eq!(ordinal_suffix(12), "th");
+        assert_eq!(ordinal_suffix(23), "rd");
+        assert_eq!(ordinal_suffix(100), "th");
+
+        let mut state = DisplayDataState::new(Vec::new(), SortBy::Score, SortOrder::Desc);
+        assert_eq!(state.position(), None);
+        let eval = evaluations(
+            &[("a.rs", "fn a1() {}\nfn a2() {}\nfn a3() {}\nfn a4() {}\n")],
+            &[0.1, 0.9, 0.5, 0.3],
+        )?;
+        state = DisplayDataState::new(eval, SortBy::Score, SortOrder::Desc);
+        assert_eq!(state.position().as_deref(), Some("1/4, 75th percentile"));
+        state.nav(Nav::End);
+        assert_eq!(state.position().as_deref(), Some("4/4, 0th percentile"));
+        Ok(())
+    }
+
+    #[test]
+    fn sparkline_shows_the_highest_value_per_bar() {
+        assert_eq!(sparkline(&[], 4), "");
+        assert_eq!(sparkline(&[0.0, 0.5, 1.0], 4), "▁▅█");
+        assert_eq!(sparkline(&[0.0, 1.0, 0.0, 0.0], 2), "█▁");
+    }
+
+    #[test]
+    fn score_markers_follow_bands() {
+        assert_eq!(score_marker(0.9), "▲");
+        assert_eq!(score_marker(HIGH_SCORE), "▲");
+        assert_eq!(score_marker(0.5), "■");
+        assert_eq!(score_marker(0.1), "▼");
+    }
+
+    #[test]
+    fn title_percent_is_floored_and_capped() {
+        let mut state = GatherDataState::new(3, false, false);
+        assert_eq!(state.percent(), 0);
+        state.count = 2;
+        assert_eq!(state.percent(), 66);
+        state.count = 4;
+        assert_eq!(state.percent(), 100);
+        assert_eq!(GatherDataState::new(0, false, false).percent(), 0);
+    }
 
-        result
+    #[test]
+    fn progress_is_capped_at_count_max() {
+        let mut state = GatherDataState::new(2, false, false);
+        state.count = 3;
+        assert_eq!(state.ratio(), 1.0);
+        assert_eq!(state.progress_label(), "2/2");
+        state.failures = 1;
+        assert_eq!(state.progress_label(), "2/2, 1 failed");
     }
 }
@@ -0,0 +1,36 @@
+use crate::{
+    args::ValidateArgs,
+    bench::{BENCH_FRAGMENT, BENCH_QUESTION},
+};
+use grepowski::ai_query::{AI, AiQueryConfig, DefaultAiQueryConfig, SchemaAiQueryConfig};
+
+/// Queries the sample fragment once with the configured schema and prints what was parsed from
+/// the response, failing if no score could be extracted.
+pub async fn run(args: ValidateArgs) -> anyhow::Result<()> {
+    let ai_query_config: Box<dyn AiQueryConfig> = match &args.json_schema_file {
+        Some(path) => SchemaAiQueryConfig::from_file(path)?.into(),
+        None => DefaultAiQueryConfig.into(),
+    };
+    let ai = AI::new(
+        args.model,
+        args.endpoint.url(),
+        args.endpoint.auth_token(),
+        args.temperature,
+        ai_query_config,
+        args.question.as_deref().unwrap_or(BENCH_QUESTION),
+    )
+    .with_auth_scheme(args.endpoint.auth_scheme.into())
+    .with_fallback_urls(args.endpoint.fallback_urls())
+    .with_proxy(args.endpoint.proxy.as_deref())?;
+
+    let result = ai
+        .query(BENCH_FRAGMENT)
+        .await
+        .map_err(|e| anyhow::anyhow!("Validation failed: {}", e))?;
+    println!("Response: {}", result.raw);
+    println!("Score: {:.3}", result.value);
+    if let Some(reason) = &result.reason {
+        println!("Reason: {}", reason);
+    }
+    Ok(())
+}
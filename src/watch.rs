@@ -0,0 +1,109 @@
+use crate::tui::TuiRequest;
+use grepowski::fragment::{Fragment, split_line_range};
+use notify_debouncer_mini::{
+    DebounceEventResult, Debouncer, new_debouncer,
+    notify::{RecommendedWatcher, RecursiveMode},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Quiet time after a change before the files are read again, so a save touching several files
+/// or an editor writing in steps triggers a single evaluation.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watches `files` and sends their fragments, as returned by `read`, in a [`TuiRequest::Reload`]
+/// whenever some of them changed. Files with a line range are read with it, but reported by
+/// their path. Watching stops when the returned debouncer is dropped.
+pub fn watch(
+    files: &[String],
+    tx_request: UnboundedSender<TuiRequest>,
+    read: impl Fn(&Path) -> Vec<Fragment> + Send + 'static,
+) -> anyhow::Result<Debouncer<RecommendedWatcher>> {
+    // editors often replace files instead of writing them, which ends a watch on the file
+    // itself, so the directories are watched and the events matched by canonical path
+    let watched = watched_files(files);
+    let dirs: HashSet<PathBuf> = watched
+        .keys()
+        .filter_map(|path| path.parent())
+        .map(Path::to_path_buf)
+        .collect();
+
+    let mut debouncer = new_debouncer(DEBOUNCE, move |result: DebounceEventResult| {
+        let Ok(events) = result else {
+            return;
+        };
+        let mut changed: Vec<&(PathBuf, Vec<PathBuf>)> = events
+            .iter()
+            .filter_map(|event| watched.get(&event.path))
+            .collect();
+        changed.sort();
+        changed.dedup();
+        if changed.is_empty() {
+            return;
+        }
+        let paths = changed.iter().map(|(path, _)| path.clone()).collect();
+        let fragments = changed
+            .iter()
+            .flat_map(|(_, files)| files)
+            .flat_map(|file| read(file))
+            .collect();
+        // fails only once the main flow is done
+        let _ = tx_request.send(TuiRequest::Reload { paths, fragments });
+    })?;
+    for dir in dirs {
+        debouncer
+            .watcher()
+            .watch(&dir, RecursiveMode::NonRecursive)?;
+    }
+    Ok(debouncer)
+}
+
+/// The path and the arguments of `files` by canonical path, several arguments can name line
+/// ranges of the same file. Files that can't be found are left out with a warning.
+fn watched_files(files: &[String]) -> HashMap<PathBuf, (PathBuf, Vec<PathBuf>)> {
+    let mut watched: HashMap<PathBuf, (PathBuf, Vec<PathBuf>)> = HashMap::new();
+    for file in files {
+        let (path, _) = split_line_range(Path::new(file));
+        match path.canonicalize() {
+            Ok(canonical) => watched
+                .entry(canonical)
+                .or_insert_with(|| (path, Vec::new()))
+                .1
+                .push(PathBuf::from(file)),
+            Err(e) => eprintln!("Warning: not watching {}: {}", file, e),
+        }
+    }
+    watched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::watched_files;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn files_with_line_ranges_are_watched_by_path() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("a.rs");
+        std::fs::write(&path, "fn one() {}\nfn two() {}\n")?;
+        let file = path.display().to_string();
+        let ranged = format!("{}:2-2", file);
+
+        let watched = watched_files(&[
+            ranged.clone(),
+            file.clone(),
+            dir.path().join("missing.rs").display().to_string(),
+        ]);
+        assert_eq!(watched.len(), 1);
+        assert_eq!(
+            watched.get(&path.canonicalize()?),
+            Some(&(path, vec![PathBuf::from(ranged), PathBuf::from(file)]))
+        );
+        Ok(())
+    }
+}